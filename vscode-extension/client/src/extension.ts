@@ -73,8 +73,9 @@ export async function activate(context: ExtensionContext) {
     // Register the server for plain text documents
     documentSelector: [{ scheme: "file", language: "pint" }],
     synchronize: {
-      // Notify the server about file changes to '.clientrc files contained in the workspace
-      fileEvents: workspace.createFileSystemWatcher("**/.clientrc"),
+      // Notify the server about changes to manifests and sources, including those made
+      // outside the editor
+      fileEvents: workspace.createFileSystemWatcher("**/{pint.toml,*.pnt}"),
     },
     traceOutputChannel,
   };
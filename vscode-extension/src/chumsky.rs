@@ -1,619 +1,1906 @@
+//! An error-tolerant parser for Pint.
+//!
+//! pintc gives up on a file at its first syntax error and drops comments and macros, so the
+//! editor features work from the tree built here instead, which keeps whatever could be parsed
+//! of half typed code such as `let x: int = storage::`. Spans are byte offsets, like pintc's.
+#![allow(clippy::result_large_err)]
+
 use chumsky::Parser;
 use chumsky::{prelude::*, stream::Stream};
 use core::fmt;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 use tower_lsp::lsp_types::SemanticTokenType;
 
-use crate::semantic_token::LEGEND_TYPE;
+use crate::semantic_token::{is_b256, legend_index, B256};
 
-/// This is the parser and interpreter for the 'Foo' language. See `tutorial.md` in the repository's root to learn
-/// about it.
 pub type Span = std::ops::Range<usize>;
+pub type Spanned<T> = (T, Span);
+
 #[derive(Debug)]
 pub struct ImCompleteSemanticToken {
     pub start: usize,
     pub length: usize,
     pub token_type: usize,
+    /// A bit set over [`crate::semantic_token::LEGEND_MODIFIER`].
+    pub modifiers: u32,
 }
+
+pub const KEYWORDS: &[&str] = &[
+    "as",
+    "b256",
+    "bool",
+    "cond",
+    "const",
+    "constraint",
+    "else",
+    "exists",
+    "false",
+    "forall",
+    "if",
+    "in",
+    "int",
+    "interface",
+    "let",
+    "macro",
+    "match",
+    "mut",
+    "nil",
+    "predicate",
+    "real",
+    "self",
+    "storage",
+    "string",
+    "true",
+    "type",
+    "union",
+    "use",
+    "where",
+];
+
+pub const PRIMITIVE_TYPES: &[&str] = &["int", "bool", "b256", "real", "string"];
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Token {
-    Null,
-    Bool(bool),
-    Num(String),
+    Ident(String),
+    /// `__sha256`
+    Intrinsic(String),
+    /// `@name`
+    MacroName(String),
+    /// `$name`
+    MacroParam(String),
+    /// `&name`
+    MacroPack(String),
+    Int(String),
+    Real(String),
     Str(String),
-    Op(String),
+    Kw(&'static str),
+    Op(&'static str),
     Ctrl(char),
-    Ident(String),
-    Fn,
-    Let,
-    Print,
-    If,
-    Else,
+    Comment(String),
 }
 
 impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Token::Null => write!(f, "null"),
-            Token::Bool(x) => write!(f, "{}", x),
-            Token::Num(n) => write!(f, "{}", n),
-            Token::Str(s) => write!(f, "{}", s),
-            Token::Op(s) => write!(f, "{}", s),
+            Token::Ident(s)
+            | Token::Intrinsic(s)
+            | Token::MacroName(s)
+            | Token::MacroParam(s)
+            | Token::MacroPack(s)
+            | Token::Int(s)
+            | Token::Real(s) => write!(f, "{}", s),
+            Token::Str(s) => write!(f, "{:?}", s),
+            Token::Kw(s) | Token::Op(s) => write!(f, "{}", s),
             Token::Ctrl(c) => write!(f, "{}", c),
-            Token::Ident(s) => write!(f, "{}", s),
-            Token::Fn => write!(f, "fn"),
-            Token::Let => write!(f, "let"),
-            Token::Print => write!(f, "print"),
-            Token::If => write!(f, "if"),
-            Token::Else => write!(f, "else"),
+            Token::Comment(s) => write!(f, "//{}", s),
         }
     }
 }
 
 fn lexer() -> impl Parser<char, Vec<(Token, Span)>, Error = Simple<char>> {
-    // A parser for numbers
-    let num = text::int(10)
-        .chain::<char, _, _>(just('.').chain(text::digits(10)).or_not().flatten())
+    let ident_start = filter(|c: &char| c.is_ascii_alphabetic() || *c == '_');
+    let ident_char = filter(|c: &char| c.is_ascii_alphanumeric() || *c == '_');
+    let word = ident_start.chain(ident_char.repeated()).collect::<String>();
+
+    // `0x1f`, `0b10`, `1_000`, `1.5` and `1.5e3`. A `.` only belongs to the number when digits
+    // follow, so ranges like `0..5` still lex as `0`, `..`, `5`.
+    let digits = filter(|c: &char| c.is_ascii_digit())
+        .chain(filter(|c: &char| c.is_ascii_digit() || *c == '_').repeated());
+    let radix = just('0')
+        .chain(one_of("xb"))
+        .chain::<char, _, _>(ident_char.repeated())
         .collect::<String>()
-        .map(Token::Num);
+        .map(Token::Int);
+    let exponent = one_of("eE")
+        .chain(one_of("+-").or_not())
+        .chain::<char, _, _>(digits);
+    let decimal = digits
+        .chain::<char, _, _>(just('.').chain(digits).or_not().flatten())
+        .chain::<char, _, _>(exponent.or_not().flatten())
+        .collect::<String>()
+        .map(|n| {
+            if n.contains(['.', 'e', 'E']) {
+                Token::Real(n)
+            } else {
+                Token::Int(n)
+            }
+        })
+        // Whatever else is stuck to the number stays part of it, as an invalid literal.
+        .then(ident_char.repeated().collect::<String>())
+        .map(|(token, rest)| match token {
+            Token::Int(n) => Token::Int(n + &rest),
+            Token::Real(n) => Token::Real(n + &rest),
+            token => token,
+        });
+    let num = radix.or(decimal);
 
-    // A parser for strings
     let str_ = just('"')
-        .ignore_then(filter(|c| *c != '"').repeated())
+        .ignore_then(
+            filter(|c: &char| *c != '"' && *c != '\\')
+                .or(just('\\').ignore_then(any()))
+                .repeated(),
+        )
         .then_ignore(just('"'))
         .collect::<String>()
         .map(Token::Str);
 
-    // A parser for operators
-    let op = one_of("+-*/!=")
-        .repeated()
-        .at_least(1)
+    let comment = just("//")
+        .ignore_then(filter(|c: &char| *c != '\n').repeated())
+        .collect::<String>()
+        .map(Token::Comment);
+
+    let macro_name = just('@')
+        .chain(word)
         .collect::<String>()
-        .map(Token::Op);
-
-    // A parser for control characters (delimiters, semicolons, etc.)
-    let ctrl = one_of("()[]{};,").map(Token::Ctrl);
-
-    // A parser for identifiers and keywords
-    let ident = text::ident().map(|ident: String| match ident.as_str() {
-        "fn" => Token::Fn,
-        "let" => Token::Let,
-        "print" => Token::Print,
-        "if" => Token::If,
-        "else" => Token::Else,
-        "true" => Token::Bool(true),
-        "false" => Token::Bool(false),
-        "null" => Token::Null,
-        _ => Token::Ident(ident),
+        .map(Token::MacroName);
+    let macro_param = just('$')
+        .chain(ident_char.repeated().at_least(1))
+        .collect::<String>()
+        .map(Token::MacroParam);
+    let macro_pack = just('&')
+        .chain(ident_char.repeated().at_least(1))
+        .collect::<String>()
+        .map(Token::MacroPack);
+
+    // Longer operators first so `::` does not lex as two `:`.
+    let op = choice((
+        just("::"),
+        just("=>"),
+        just("->"),
+        just("=="),
+        just("!="),
+        just("<="),
+        just(">="),
+        just("&&"),
+        just("||"),
+        just(".."),
+        just("="),
+        just("<"),
+        just(">"),
+        just("+"),
+        just("-"),
+        just("*"),
+        just("/"),
+        just("%"),
+        just("!"),
+        just("~"),
+    ))
+    .map(Token::Op);
+
+    let ctrl = one_of("()[]{};,:.@'|?").map(Token::Ctrl);
+
+    let ident = word.map(|ident: String| {
+        if let Some(keyword) = KEYWORDS.iter().find(|keyword| **keyword == ident) {
+            Token::Kw(keyword)
+        } else if ident.starts_with("__") {
+            Token::Intrinsic(ident)
+        } else {
+            Token::Ident(ident)
+        }
     });
 
-    // A single token can be one of the above
-    let token = num
+    let token = comment
         .or(str_)
+        .or(num)
         .or(op)
+        .or(macro_pack)
+        .or(macro_name)
+        .or(macro_param)
         .or(ctrl)
         .or(ident)
         .recover_with(skip_then_retry_until([]));
 
-    let comment = just("//").then(take_until(just('\n'))).padded();
-
     token
-        .padded_by(comment.repeated())
         .map_with_span(|tok, span| (tok, span))
         .padded()
         .repeated()
+        .then_ignore(end())
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub enum Value {
-    Null,
-    Bool(bool),
-    Num(f64),
-    Str(String),
-    List(Vec<Value>),
-    Func(String),
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ident {
+    pub name: String,
+    pub span: Span,
 }
 
-impl std::fmt::Display for Value {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Self::Null => write!(f, "null"),
-            Self::Bool(x) => write!(f, "{}", x),
-            Self::Num(x) => write!(f, "{}", x),
-            Self::Str(x) => write!(f, "{}", x),
-            Self::List(xs) => write!(
-                f,
-                "[{}]",
-                xs.iter()
-                    .map(|x| x.to_string())
-                    .collect::<Vec<_>>()
-                    .join(", ")
-            ),
-            Self::Func(name) => write!(f, "<function: {}>", name),
+/// `a::b::c`, possibly absolute (`::a`) or cut short while typing (`a::`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Path {
+    pub absolute: bool,
+    pub segments: Vec<Ident>,
+    /// Whether the path ends in `::` with the next segment still to be typed.
+    pub incomplete: bool,
+    pub span: Span,
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.absolute {
+            write!(f, "::")?;
         }
+        let segments = self
+            .segments
+            .iter()
+            .map(|segment| segment.name.as_str())
+            .collect::<Vec<_>>();
+        write!(f, "{}", segments.join("::"))?;
+        if self.incomplete {
+            write!(f, "::")?;
+        }
+        Ok(())
+    }
+}
+
+impl Path {
+    /// The last segment, e.g. `c` in `a::b::c`.
+    pub fn name(&self) -> Option<&Ident> {
+        self.segments.last()
     }
 }
 
 #[derive(Clone, Debug)]
+pub enum Type {
+    Error(Span),
+    /// `int`, `bool`, `b256`, `real` or `string`.
+    Primitive(String, Span),
+    Custom(Path),
+    Tuple(Vec<(Option<Ident>, Type)>, Span),
+    /// `ty[size]`
+    Array {
+        ty: Box<Type>,
+        size: Box<Expr>,
+        span: Span,
+    },
+    /// `ty[]`
+    Vector {
+        ty: Box<Type>,
+        span: Span,
+    },
+    /// `( from => to )`
+    Map {
+        from: Box<Type>,
+        to: Box<Type>,
+        span: Span,
+    },
+}
+
+impl Type {
+    pub fn span(&self) -> Span {
+        match self {
+            Type::Error(span)
+            | Type::Primitive(_, span)
+            | Type::Tuple(_, span)
+            | Type::Array { span, .. }
+            | Type::Vector { span, .. }
+            | Type::Map { span, .. } => span.clone(),
+            Type::Custom(path) => path.span.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Error(_) => write!(f, "_"),
+            Type::Primitive(name, _) => write!(f, "{}", name),
+            Type::Custom(path) => write!(f, "{}", path),
+            Type::Tuple(fields, _) => {
+                let fields = fields
+                    .iter()
+                    .map(|(name, ty)| match name {
+                        Some(name) => format!("{}: {}", name.name, ty),
+                        None => ty.to_string(),
+                    })
+                    .collect::<Vec<_>>();
+                write!(f, "{{ {} }}", fields.join(", "))
+            }
+            Type::Array { ty, size, .. } => match size.as_ref() {
+                Expr::Literal(Literal::Int(size), _) => write!(f, "{}[{}]", ty, size),
+                Expr::Path(path) => write!(f, "{}[{}]", ty, path),
+                _ => write!(f, "{}[_]", ty),
+            },
+            Type::Vector { ty, .. } => write!(f, "{}[]", ty),
+            Type::Map { from, to, .. } => write!(f, "( {} => {} )", from, to),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Literal {
+    Int(String),
+    Real(String),
+    Str(String),
+    Bool(bool),
+    Nil,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    /// A leading `+`, which pintc rejects.
+    Plus,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BinaryOp {
     Add,
     Sub,
     Mul,
     Div,
+    Mod,
     Eq,
     NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    And,
+    Or,
 }
 
-pub type Spanned<T> = (T, Span);
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GeneratorKind {
+    ForAll,
+    Exists,
+}
 
-// An expression node in the AST. Children are spanned so we can generate useful runtime errors.
-#[derive(Debug)]
+/// `@name(arg; arg)`, as an expression or a declaration.
+#[derive(Clone, Debug)]
+pub struct MacroCall {
+    pub name: Path,
+    /// The span of the `@name` token.
+    pub name_span: Span,
+    pub args: Vec<Expr>,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub struct MatchArm {
+    pub pattern: Path,
+    pub binding: Option<Ident>,
+    /// Constraints of an arm written as a block, `=> { constraint a; b }`.
+    pub constraints: Vec<Expr>,
+    pub expr: Expr,
+}
+
+#[derive(Clone, Debug)]
 pub enum Expr {
-    Error,
-    Value(Value),
-    List(Vec<Spanned<Self>>),
-    Local(Spanned<String>),
-    Let(String, Box<Spanned<Self>>, Box<Spanned<Self>>, Span),
-    Then(Box<Spanned<Self>>, Box<Spanned<Self>>),
-    Binary(Box<Spanned<Self>>, BinaryOp, Box<Spanned<Self>>),
-    Call(Box<Spanned<Self>>, Spanned<Vec<Spanned<Self>>>),
-    If(Box<Spanned<Self>>, Box<Spanned<Self>>, Box<Spanned<Self>>),
-    Print(Box<Spanned<Self>>),
-}
-
-#[allow(unused)]
+    Error(Span),
+    Literal(Literal, Span),
+    /// A local, const, union variant or anything else named by a path.
+    Path(Path),
+    /// `storage::name` or `mut storage::name`. `name` is missing while it is being typed.
+    Storage {
+        mutable: bool,
+        name: Option<Ident>,
+        span: Span,
+    },
+    /// `Interface@[address]::storage::name`
+    ExternalStorage {
+        interface: Path,
+        address: Box<Expr>,
+        name: Option<Ident>,
+        span: Span,
+    },
+    Unary {
+        op: UnaryOp,
+        expr: Box<Expr>,
+        span: Span,
+    },
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+        span: Span,
+    },
+    /// `expr'`
+    NextState {
+        expr: Box<Expr>,
+        span: Span,
+    },
+    /// `expr[index]`
+    Index {
+        expr: Box<Expr>,
+        index: Option<Box<Expr>>,
+        span: Span,
+    },
+    /// `expr.field` or `expr.0`. `field` is missing while it is being typed.
+    Field {
+        expr: Box<Expr>,
+        field: Option<Ident>,
+        span: Span,
+    },
+    IntrinsicCall {
+        name: Ident,
+        args: Vec<Expr>,
+        span: Span,
+    },
+    MacroCall(MacroCall),
+    /// `Pred@[]()` or `Interface@[c]::Pred@[p](args)`
+    PredicateCall {
+        path: Path,
+        addresses: Vec<Expr>,
+        predicate: Option<Ident>,
+        args: Vec<Expr>,
+        span: Span,
+    },
+    /// `Union::Variant(value)`
+    UnionVariant {
+        path: Path,
+        value: Box<Expr>,
+        span: Span,
+    },
+    Array(Vec<Expr>, Span),
+    Tuple(Vec<(Option<Ident>, Expr)>, Span),
+    /// `condition ? then : else`
+    Select {
+        condition: Box<Expr>,
+        then_expr: Box<Expr>,
+        else_expr: Box<Expr>,
+        span: Span,
+    },
+    Cond {
+        branches: Vec<(Expr, Expr)>,
+        else_expr: Box<Expr>,
+        span: Span,
+    },
+    Match {
+        expr: Box<Expr>,
+        arms: Vec<MatchArm>,
+        else_arm: Option<(Vec<Expr>, Box<Expr>)>,
+        span: Span,
+    },
+    Generator {
+        kind: GeneratorKind,
+        ranges: Vec<(Ident, Expr)>,
+        conditions: Vec<Expr>,
+        body: Box<Expr>,
+        span: Span,
+    },
+    In {
+        value: Box<Expr>,
+        collection: Box<Expr>,
+        span: Span,
+    },
+    Range {
+        lb: Box<Expr>,
+        ub: Box<Expr>,
+        span: Span,
+    },
+    Cast {
+        value: Box<Expr>,
+        ty: Type,
+        span: Span,
+    },
+}
+
 impl Expr {
-    /// Returns `true` if the expr is [`Error`].
-    ///
-    /// [`Error`]: Expr::Error
-    fn is_error(&self) -> bool {
-        matches!(self, Self::Error)
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Error(span)
+            | Expr::Literal(_, span)
+            | Expr::Storage { span, .. }
+            | Expr::ExternalStorage { span, .. }
+            | Expr::Unary { span, .. }
+            | Expr::Binary { span, .. }
+            | Expr::NextState { span, .. }
+            | Expr::Index { span, .. }
+            | Expr::Field { span, .. }
+            | Expr::IntrinsicCall { span, .. }
+            | Expr::PredicateCall { span, .. }
+            | Expr::UnionVariant { span, .. }
+            | Expr::Array(_, span)
+            | Expr::Tuple(_, span)
+            | Expr::Select { span, .. }
+            | Expr::Cond { span, .. }
+            | Expr::Match { span, .. }
+            | Expr::Generator { span, .. }
+            | Expr::In { span, .. }
+            | Expr::Range { span, .. }
+            | Expr::Cast { span, .. } => span.clone(),
+            Expr::Path(path) => path.span.clone(),
+            Expr::MacroCall(call) => call.span.clone(),
+        }
     }
 
-    /// Returns `true` if the expr is [`Let`].
-    ///
-    /// [`Let`]: Expr::Let
-    fn is_let(&self) -> bool {
-        matches!(self, Self::Let(..))
+    /// The expressions directly inside this one.
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Error(_) | Expr::Literal(..) | Expr::Path(_) | Expr::Storage { .. } => vec![],
+            Expr::ExternalStorage { address, .. } => vec![address],
+            Expr::Unary { expr, .. } | Expr::NextState { expr, .. } => vec![expr],
+            Expr::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Expr::Index { expr, index, .. } => {
+                let mut children = vec![expr.as_ref()];
+                children.extend(index.as_deref());
+                children
+            }
+            Expr::Field { expr, .. } => vec![expr],
+            Expr::IntrinsicCall { args, .. } | Expr::Array(args, _) => args.iter().collect(),
+            Expr::MacroCall(call) => call.args.iter().collect(),
+            Expr::PredicateCall {
+                addresses, args, ..
+            } => addresses.iter().chain(args).collect(),
+            Expr::UnionVariant { value, .. } => vec![value],
+            Expr::Tuple(fields, _) => fields.iter().map(|(_, expr)| expr).collect(),
+            Expr::Select {
+                condition,
+                then_expr,
+                else_expr,
+                ..
+            } => vec![condition, then_expr, else_expr],
+            Expr::Cond {
+                branches,
+                else_expr,
+                ..
+            } => branches
+                .iter()
+                .flat_map(|(condition, result)| [condition, result])
+                .chain([else_expr.as_ref()])
+                .collect(),
+            Expr::Match {
+                expr,
+                arms,
+                else_arm,
+                ..
+            } => {
+                let mut children = vec![expr.as_ref()];
+                for arm in arms {
+                    children.extend(&arm.constraints);
+                    children.push(&arm.expr);
+                }
+                if let Some((constraints, expr)) = else_arm {
+                    children.extend(constraints);
+                    children.push(expr);
+                }
+                children
+            }
+            Expr::Generator {
+                ranges,
+                conditions,
+                body,
+                ..
+            } => ranges
+                .iter()
+                .map(|(_, range)| range)
+                .chain(conditions)
+                .chain([body.as_ref()])
+                .collect(),
+            Expr::In {
+                value, collection, ..
+            } => vec![value, collection],
+            Expr::Range { lb, ub, .. } => vec![lb, ub],
+            Expr::Cast { value, .. } => vec![value],
+        }
     }
 
-    /// Returns `true` if the expr is [`Value`].
-    ///
-    /// [`Value`]: Expr::Value
-    fn is_value(&self) -> bool {
-        matches!(self, Self::Value(..))
+    /// Visit this expression and everything inside it, outermost first.
+    pub fn walk<'a>(&'a self, f: &mut impl FnMut(&'a Expr)) {
+        f(self);
+        for child in self.children() {
+            child.walk(f);
+        }
     }
+}
 
-    fn try_into_value(self) -> Result<Value, Self> {
-        if let Self::Value(v) = self {
-            Ok(v)
-        } else {
-            Err(self)
+#[derive(Clone, Debug)]
+pub struct Param {
+    pub name: Ident,
+    pub ty: Option<Type>,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub struct StorageVar {
+    pub name: Ident,
+    pub ty: Option<Type>,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub struct UnionVariant {
+    pub name: Ident,
+    pub ty: Option<Type>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PredicateInterface {
+    pub name: Ident,
+    pub params: Vec<Param>,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub enum UseTree {
+    Name(Ident),
+    Path { prefix: Ident, suffix: Box<UseTree> },
+    Group(Vec<UseTree>),
+    Alias { name: Ident, alias: Ident },
+}
+
+#[derive(Clone, Debug)]
+pub struct MatchDeclArm {
+    pub pattern: Path,
+    pub binding: Option<Ident>,
+    pub body: Vec<Decl>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Decl {
+    /// Tokens that could not be parsed as anything.
+    Error(Span),
+    Use {
+        absolute: bool,
+        tree: UseTree,
+        span: Span,
+    },
+    Const {
+        name: Ident,
+        ty: Option<Type>,
+        init: Option<Expr>,
+        span: Span,
+    },
+    NewType {
+        name: Ident,
+        ty: Option<Type>,
+        span: Span,
+    },
+    Union {
+        name: Ident,
+        variants: Vec<UnionVariant>,
+        span: Span,
+    },
+    Storage {
+        vars: Vec<StorageVar>,
+        span: Span,
+    },
+    Interface {
+        name: Ident,
+        storage: Vec<StorageVar>,
+        predicates: Vec<PredicateInterface>,
+        span: Span,
+    },
+    Predicate {
+        name: Ident,
+        params: Vec<Param>,
+        body: Vec<Decl>,
+        span: Span,
+    },
+    Macro {
+        name: Ident,
+        params: Vec<Ident>,
+        pack: Option<Ident>,
+        body: Vec<Decl>,
+        span: Span,
+    },
+    Let {
+        name: Ident,
+        ty: Option<Type>,
+        init: Option<Expr>,
+        span: Span,
+    },
+    Constraint {
+        expr: Expr,
+        span: Span,
+    },
+    If {
+        condition: Expr,
+        then_block: Vec<Decl>,
+        else_block: Option<Vec<Decl>>,
+        span: Span,
+    },
+    Match {
+        expr: Expr,
+        arms: Vec<MatchDeclArm>,
+        else_block: Option<Vec<Decl>>,
+        span: Span,
+    },
+    /// A bare expression: a macro call used as a declaration, or the value of a macro body.
+    Expr(Expr),
+}
+
+impl Decl {
+    pub fn span(&self) -> Span {
+        match self {
+            Decl::Error(span)
+            | Decl::Use { span, .. }
+            | Decl::Const { span, .. }
+            | Decl::NewType { span, .. }
+            | Decl::Union { span, .. }
+            | Decl::Storage { span, .. }
+            | Decl::Interface { span, .. }
+            | Decl::Predicate { span, .. }
+            | Decl::Macro { span, .. }
+            | Decl::Let { span, .. }
+            | Decl::Constraint { span, .. }
+            | Decl::If { span, .. }
+            | Decl::Match { span, .. } => span.clone(),
+            Decl::Expr(expr) => expr.span(),
         }
     }
 
-    fn as_value(&self) -> Option<&Value> {
-        if let Self::Value(v) = self {
-            Some(v)
-        } else {
-            None
+    /// The declarations nested in this one, e.g. the body of a predicate.
+    pub fn children(&self) -> Vec<&Decl> {
+        match self {
+            Decl::Predicate { body, .. } | Decl::Macro { body, .. } => body.iter().collect(),
+            Decl::If {
+                then_block,
+                else_block,
+                ..
+            } => then_block
+                .iter()
+                .chain(else_block.iter().flatten())
+                .collect(),
+            Decl::Match {
+                arms, else_block, ..
+            } => arms
+                .iter()
+                .flat_map(|arm| &arm.body)
+                .chain(else_block.iter().flatten())
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// The expressions this declaration holds directly, not counting nested declarations.
+    pub fn exprs(&self) -> Vec<&Expr> {
+        match self {
+            Decl::Const { init, .. } | Decl::Let { init, .. } => init.iter().collect(),
+            Decl::Constraint { expr, .. } | Decl::Expr(expr) => vec![expr],
+            Decl::If { condition, .. } => vec![condition],
+            Decl::Match { expr, .. } => vec![expr],
+            _ => vec![],
+        }
+    }
+
+    /// The types written in this declaration itself, such as parameter and `let` annotations.
+    pub fn types(&self) -> Vec<&Type> {
+        match self {
+            Decl::Const { ty, .. } | Decl::Let { ty, .. } | Decl::NewType { ty, .. } => {
+                ty.iter().collect()
+            }
+            Decl::Union { variants, .. } => variants
+                .iter()
+                .filter_map(|variant| variant.ty.as_ref())
+                .collect(),
+            Decl::Storage { vars, .. } => vars.iter().filter_map(|var| var.ty.as_ref()).collect(),
+            Decl::Interface {
+                storage,
+                predicates,
+                ..
+            } => storage
+                .iter()
+                .filter_map(|var| var.ty.as_ref())
+                .chain(
+                    predicates
+                        .iter()
+                        .flat_map(|predicate| &predicate.params)
+                        .filter_map(|param| param.ty.as_ref()),
+                )
+                .collect(),
+            Decl::Predicate { params, .. } => {
+                params.iter().filter_map(|param| param.ty.as_ref()).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Visit this declaration and every declaration nested in it, outermost first.
+    pub fn walk<'a>(&'a self, f: &mut impl FnMut(&'a Decl)) {
+        f(self);
+        for child in self.children() {
+            child.walk(f);
         }
     }
 }
 
-// A function node in the AST.
-#[derive(Debug)]
-pub struct Func {
-    pub args: Vec<Spanned<String>>,
-    pub body: Spanned<Expr>,
-    pub name: Spanned<String>,
-    pub span: Span,
+pub type Ast = Vec<Decl>;
+
+fn kw(keyword: &'static str) -> impl Parser<Token, Token, Error = Simple<Token>> + Clone {
+    just(Token::Kw(keyword))
 }
 
-fn expr_parser() -> impl Parser<Token, Spanned<Expr>, Error = Simple<Token>> + Clone {
-    recursive(|expr| {
-        let raw_expr = recursive(|raw_expr| {
-            let val = filter_map(|span, tok| match tok {
-                Token::Null => Ok(Expr::Value(Value::Null)),
-                Token::Bool(x) => Ok(Expr::Value(Value::Bool(x))),
-                Token::Num(n) => Ok(Expr::Value(Value::Num(n.parse().unwrap()))),
-                Token::Str(s) => Ok(Expr::Value(Value::Str(s))),
-                _ => Err(Simple::expected_input_found(span, Vec::new(), Some(tok))),
-            })
-            .labelled("value");
+fn op(op: &'static str) -> impl Parser<Token, Token, Error = Simple<Token>> + Clone {
+    just(Token::Op(op))
+}
 
-            let ident = filter_map(|span, tok| match tok {
-                Token::Ident(ident) => Ok((ident, span)),
-                _ => Err(Simple::expected_input_found(span, Vec::new(), Some(tok))),
-            })
-            .labelled("identifier");
+fn ctrl(c: char) -> impl Parser<Token, Token, Error = Simple<Token>> + Clone {
+    just(Token::Ctrl(c))
+}
 
-            // A list of expressions
-            let items = expr
-                .clone()
-                .chain(just(Token::Ctrl(',')).ignore_then(expr.clone()).repeated())
-                .then_ignore(just(Token::Ctrl(',')).or_not())
-                .or_not()
-                .map(|item| item.unwrap_or_default());
-
-            // A let expression
-            let let_ = just(Token::Let)
-                .ignore_then(ident)
-                .then_ignore(just(Token::Op("=".to_string())))
-                .then(raw_expr)
-                .then_ignore(just(Token::Ctrl(';')))
-                .then(expr.clone())
-                .map(|((name, val), body)| {
-                    Expr::Let(name.0, Box::new(val), Box::new(body), name.1)
-                });
+fn ident() -> impl Parser<Token, Ident, Error = Simple<Token>> + Clone {
+    // Macro parameters stand in for identifiers inside macro bodies.
+    select! {
+        Token::Ident(name) => name,
+        Token::MacroParam(name) => name,
+    }
+    .map_with_span(|name, span| Ident { name, span })
+    .labelled("identifier")
+}
 
-            let list = items
-                .clone()
-                .delimited_by(just(Token::Ctrl('[')), just(Token::Ctrl(']')))
-                .map(Expr::List);
-
-            // 'Atoms' are expressions that contain no ambiguity
-            let atom = val
-                .or(ident.map(Expr::Local))
-                .or(let_)
-                .or(list)
-                // In Nano Rust, `print` is just a keyword, just like Python 2, for simplicity
-                .or(just(Token::Print)
-                    .ignore_then(
-                        expr.clone()
-                            .delimited_by(just(Token::Ctrl('(')), just(Token::Ctrl(')'))),
-                    )
-                    .map(|expr| Expr::Print(Box::new(expr))))
-                .map_with_span(|expr, span| (expr, span))
-                // Atoms can also just be normal expressions, but surrounded with parentheses
-                .or(expr
-                    .clone()
-                    .delimited_by(just(Token::Ctrl('(')), just(Token::Ctrl(')'))))
-                // Attempt to recover anything that looks like a parenthesised expression but contains errors
-                .recover_with(nested_delimiters(
-                    Token::Ctrl('('),
-                    Token::Ctrl(')'),
-                    [
-                        (Token::Ctrl('['), Token::Ctrl(']')),
-                        (Token::Ctrl('{'), Token::Ctrl('}')),
-                    ],
-                    |span| (Expr::Error, span),
+fn path() -> impl Parser<Token, Path, Error = Simple<Token>> + Clone {
+    op("::")
+        .or_not()
+        .then(ident().separated_by(op("::")).at_least(1))
+        .then(op("::").or_not())
+        .map_with_span(|((absolute, segments), trailing), span| Path {
+            absolute: absolute.is_some(),
+            segments,
+            incomplete: trailing.is_some(),
+            span,
+        })
+}
+
+/// `item` followed by `close`. A missing `close` is reported but does not fail the parse, so
+/// unfinished code keeps what it has.
+fn closed<O>(
+    item: impl Parser<Token, O, Error = Simple<Token>> + Clone,
+    close: char,
+) -> impl Parser<Token, O, Error = Simple<Token>> + Clone {
+    item.then(ctrl(close).or_not())
+        .validate(move |(item, closed), span: Span, emit| {
+            if closed.is_none() {
+                emit(Simple::custom(
+                    span.end..span.end,
+                    format!("expected `{}`", close),
                 ))
-                // Attempt to recover anything that looks like a list but contains errors
-                .recover_with(nested_delimiters(
-                    Token::Ctrl('['),
-                    Token::Ctrl(']'),
-                    [
-                        (Token::Ctrl('('), Token::Ctrl(')')),
-                        (Token::Ctrl('{'), Token::Ctrl('}')),
-                    ],
-                    |span| (Expr::Error, span),
-                ));
-
-            // Function calls have very high precedence so we prioritise them
-            let call = atom
-                .then(
-                    items
-                        .delimited_by(just(Token::Ctrl('(')), just(Token::Ctrl(')')))
-                        .map_with_span(|args, span| (args, span))
-                        .repeated(),
-                )
-                .foldl(|f, args| {
-                    let span = f.1.start..args.1.end;
-                    (Expr::Call(Box::new(f), args), span)
-                });
+            }
+            item
+        })
+}
 
-            // Product ops (multiply and divide) have equal precedence
-            let op = just(Token::Op("*".to_string()))
-                .to(BinaryOp::Mul)
-                .or(just(Token::Op("/".to_string())).to(BinaryOp::Div));
-            let product = call
-                .clone()
-                .then(op.then(call).repeated())
-                .foldl(|a, (op, b)| {
-                    let span = a.1.start..b.1.end;
-                    (Expr::Binary(Box::new(a), op, Box::new(b)), span)
-                });
+/// `open item, item, ... close` with an optional trailing comma.
+fn list<O>(
+    item: impl Parser<Token, O, Error = Simple<Token>> + Clone,
+    open: char,
+    close: char,
+) -> impl Parser<Token, Vec<O>, Error = Simple<Token>> + Clone {
+    closed(
+        ctrl(open).ignore_then(item.separated_by(ctrl(',')).allow_trailing()),
+        close,
+    )
+}
 
-            // Sum ops (add and subtract) have equal precedence
-            let op = just(Token::Op("+".to_string()))
-                .to(BinaryOp::Add)
-                .or(just(Token::Op("-".to_string())).to(BinaryOp::Sub));
-            let sum = product
-                .clone()
-                .then(op.then(product).repeated())
-                .foldl(|a, (op, b)| {
-                    let span = a.1.start..b.1.end;
-                    (Expr::Binary(Box::new(a), op, Box::new(b)), span)
-                });
+fn type_parser(
+    expr: impl Parser<Token, Expr, Error = Simple<Token>> + Clone + 'static,
+) -> impl Parser<Token, Type, Error = Simple<Token>> + Clone {
+    recursive(|ty| {
+        let primitive = select! {
+            Token::Kw(keyword) if PRIMITIVE_TYPES.contains(&keyword) => keyword.to_string(),
+        }
+        .map_with_span(Type::Primitive);
 
-            // Comparison ops (equal, not-equal) have equal precedence
-            let op = just(Token::Op("==".to_string()))
-                .to(BinaryOp::Eq)
-                .or(just(Token::Op("!=".to_string())).to(BinaryOp::NotEq));
+        // A named field keeps its name while its type is still being typed.
+        let named_field = ident()
+            .then_ignore(ctrl(':'))
+            .then(ty.clone().or_not())
+            .map_with_span(|(name, ty), span: Span| {
+                (Some(name), ty.unwrap_or(Type::Error(span.end..span.end)))
+            });
+        let tuple = list(named_field.or(ty.clone().map(|ty| (None, ty))), '{', '}')
+            .map_with_span(Type::Tuple);
 
-            sum.clone()
-                .then(op.then(sum).repeated())
-                .foldl(|a, (op, b)| {
-                    let span = a.1.start..b.1.end;
-                    (Expr::Binary(Box::new(a), op, Box::new(b)), span)
-                })
+        let map = closed(
+            ctrl('(')
+                .ignore_then(ty.clone())
+                .then_ignore(op("=>"))
+                .then(ty.clone().or_not()),
+            ')',
+        )
+        .map_with_span(|(from, to), span: Span| Type::Map {
+            from: Box::new(from),
+            to: Box::new(to.unwrap_or(Type::Error(span.end..span.end))),
+            span,
         });
 
-        // Blocks are expressions but delimited with braces
-        let block = expr
-            .clone()
-            .delimited_by(just(Token::Ctrl('{')), just(Token::Ctrl('}')))
-            // Attempt to recover anything that looks like a block but contains errors
-            .recover_with(nested_delimiters(
-                Token::Ctrl('{'),
-                Token::Ctrl('}'),
-                [
-                    (Token::Ctrl('('), Token::Ctrl(')')),
-                    (Token::Ctrl('['), Token::Ctrl(']')),
-                ],
-                |span| (Expr::Error, span),
-            ));
+        let atom = choice((primitive, tuple, map, path().map(Type::Custom)));
 
-        let if_ = recursive(|if_| {
-            just(Token::If)
-                .ignore_then(expr.clone())
-                .then(block.clone())
+        atom.then(
+            closed(ctrl('[').ignore_then(expr.or_not()), ']')
+                .map_with_span(|size, span| (size, span))
+                .repeated(),
+        )
+        .foldl(|ty, (size, span)| {
+            let span = ty.span().start..span.end;
+            match size {
+                Some(size) => Type::Array {
+                    ty: Box::new(ty),
+                    size: Box::new(size),
+                    span,
+                },
+                None => Type::Vector {
+                    ty: Box::new(ty),
+                    span,
+                },
+            }
+        })
+        .boxed()
+    })
+}
+
+/// What follows a path in an expression, e.g. the `@[addr](args)` of a predicate call.
+enum PathSuffix {
+    Call {
+        address: Option<Expr>,
+        external: Option<(Ident, Option<Expr>)>,
+        args: Vec<Expr>,
+    },
+    Storage {
+        address: Option<Expr>,
+        name: Option<Ident>,
+    },
+    Value(Expr),
+}
+
+/// A postfix operator, applied to the expression before it.
+#[derive(Clone)]
+enum Postfix {
+    Index(Option<Expr>),
+    Field(Option<Ident>),
+    NextState,
+}
+
+fn expr_parser() -> impl Parser<Token, Expr, Error = Simple<Token>> + Clone {
+    recursive(|expr: Recursive<Token, Expr, Simple<Token>>| {
+        let ty = type_parser(expr.clone());
+
+        let literal = select! {
+            Token::Int(n) => Literal::Int(n),
+            Token::Real(n) => Literal::Real(n),
+            Token::Str(s) => Literal::Str(s),
+            Token::Kw("true") => Literal::Bool(true),
+            Token::Kw("false") => Literal::Bool(false),
+            Token::Kw("nil") => Literal::Nil,
+        }
+        .map_with_span(Expr::Literal);
+
+        let args = list(expr.clone(), '(', ')');
+
+        let storage = kw("mut")
+            .or_not()
+            .then_ignore(kw("storage"))
+            .then(op("::").ignore_then(ident().or_not()).or_not())
+            .map_with_span(|(mutable, name), span| Expr::Storage {
+                mutable: mutable.is_some(),
+                name: name.flatten(),
+                span,
+            });
+
+        let address = closed(
+            ctrl('@')
+                .ignore_then(ctrl('['))
+                .ignore_then(expr.clone().or_not()),
+            ']',
+        );
+
+        // Storage of other contracts, predicate calls and union values all start with a path,
+        // so the path is parsed once and told apart by what follows it.
+        let path_suffix = choice((
+            address
+                .clone()
+                .then(op("::").ignore_then(ident()).then(address.clone()).or_not())
+                .then(args.clone())
+                .map(|((address, external), args)| PathSuffix::Call {
+                    address,
+                    external,
+                    args,
+                }),
+            address
+                .then_ignore(op("::"))
                 .then(
-                    just(Token::Else)
-                        .ignore_then(block.clone().or(if_))
+                    kw("storage")
+                        .ignore_then(op("::").ignore_then(ident().or_not()).or_not())
                         .or_not(),
                 )
-                .map_with_span(|((cond, a), b), span| {
-                    (
-                        Expr::If(
-                            Box::new(cond),
-                            Box::new(a),
-                            Box::new(match b {
-                                Some(b) => b,
-                                // If an `if` expression has no trailing `else` block, we magic up one that just produces null
-                                None => (Expr::Value(Value::Null), span.clone()),
-                            }),
-                        ),
+                .map(|(address, name)| PathSuffix::Storage {
+                    address,
+                    name: name.flatten().flatten(),
+                }),
+            closed(ctrl('(').ignore_then(expr.clone()), ')').map(PathSuffix::Value),
+        ));
+
+        let path_expr =
+            path()
+                .then(path_suffix.or_not())
+                .map_with_span(|(path, suffix), span: Span| match suffix {
+                    None => Expr::Path(path),
+                    Some(PathSuffix::Call {
+                        address,
+                        external,
+                        args,
+                    }) => {
+                        let mut addresses = address.into_iter().collect::<Vec<_>>();
+                        let predicate = external.map(|(predicate, address)| {
+                            addresses.extend(address);
+                            predicate
+                        });
+                        Expr::PredicateCall {
+                            path,
+                            addresses,
+                            predicate,
+                            args,
+                            span,
+                        }
+                    }
+                    Some(PathSuffix::Storage { address, name }) => Expr::ExternalStorage {
+                        interface: path,
+                        address: Box::new(address.unwrap_or(Expr::Error(span.clone()))),
+                        name,
+                        span,
+                    },
+                    Some(PathSuffix::Value(value)) => Expr::UnionVariant {
+                        path,
+                        value: Box::new(value),
                         span,
+                    },
+                });
+
+        let intrinsic_call = select! { Token::Intrinsic(name) => name }
+            .map_with_span(|name, span| Ident { name, span })
+            .then(args.clone())
+            .map_with_span(|(name, args), span| Expr::IntrinsicCall { name, args, span });
+
+        let macro_call = macro_call_parser(expr.clone()).map(Expr::MacroCall);
+
+        let array = list(expr.clone(), '[', ']').map_with_span(Expr::Array);
+
+        let tuple = list(
+            ident().then_ignore(ctrl(':')).or_not().then(expr.clone()),
+            '{',
+            '}',
+        )
+        .map_with_span(Expr::Tuple);
+
+        let cond = kw("cond")
+            .ignore_then(closed(
+                ctrl('{')
+                    .ignore_then(
+                        expr.clone()
+                            .then_ignore(op("=>"))
+                            .then(expr.clone())
+                            .then_ignore(ctrl(','))
+                            .repeated(),
                     )
-                })
-        });
+                    .then(
+                        kw("else")
+                            .ignore_then(op("=>"))
+                            .ignore_then(expr.clone())
+                            .then_ignore(ctrl(',').or_not())
+                            .or_not(),
+                    ),
+                '}',
+            ))
+            .map_with_span(|(branches, else_expr), span: Span| Expr::Cond {
+                branches,
+                else_expr: Box::new(else_expr.unwrap_or(Expr::Error(span.end..span.end))),
+                span,
+            });
 
-        // Both blocks and `if` are 'block expressions' and can appear in the place of statements
-        let block_expr = block.or(if_).labelled("block");
+        // `=> expr` or `=> { constraint a; constraint b; expr }`
+        let arm_body = choice((
+            closed(
+                ctrl('{')
+                    .ignore_then(
+                        kw("constraint")
+                            .ignore_then(expr.clone())
+                            .then_ignore(ctrl(';'))
+                            .repeated(),
+                    )
+                    .then(expr.clone()),
+                '}',
+            ),
+            expr.clone().map(|expr| (vec![], expr)),
+        ));
 
-        let block_chain = block_expr
-            .clone()
-            .then(block_expr.clone().repeated())
-            .foldl(|a, b| {
-                let span = a.1.start..b.1.end;
-                (Expr::Then(Box::new(a), Box::new(b)), span)
+        let match_arm = path()
+            .then(closed(ctrl('(').ignore_then(ident()), ')').or_not())
+            .then_ignore(op("=>"))
+            .then(arm_body.clone())
+            .then_ignore(ctrl(',').or_not())
+            .map(|((pattern, binding), (constraints, expr))| MatchArm {
+                pattern,
+                binding,
+                constraints,
+                expr,
             });
 
-        block_chain
-            // Expressions, chained by semicolons, are statements
-            .or(raw_expr.clone())
-            .then(just(Token::Ctrl(';')).ignore_then(expr.or_not()).repeated())
-            .foldl(|a, b| {
-                let span = a.1.clone(); // TODO: Not correct
-                (
-                    Expr::Then(
-                        Box::new(a),
-                        Box::new(match b {
-                            Some(b) => b,
-                            None => (Expr::Value(Value::Null), span.clone()),
-                        }),
-                    ),
+        let match_ = kw("match")
+            .ignore_then(expr.clone())
+            .then(closed(
+                ctrl('{').ignore_then(match_arm.repeated()).then(
+                    kw("else")
+                        .ignore_then(op("=>"))
+                        .ignore_then(arm_body)
+                        .then_ignore(ctrl(',').or_not())
+                        .or_not(),
+                ),
+                '}',
+            ))
+            .map_with_span(|(expr, (arms, else_arm)), span| Expr::Match {
+                expr: Box::new(expr),
+                arms,
+                else_arm: else_arm.map(|(constraints, expr)| (constraints, Box::new(expr))),
+                span,
+            });
+
+        let range = expr_range(expr.clone());
+
+        let generator = kw("forall")
+            .to(GeneratorKind::ForAll)
+            .or(kw("exists").to(GeneratorKind::Exists))
+            .then(
+                ident()
+                    .then_ignore(kw("in"))
+                    .then(range.clone())
+                    .separated_by(ctrl(','))
+                    .at_least(1),
+            )
+            .then(
+                kw("where")
+                    .ignore_then(expr.clone().separated_by(ctrl(',')).at_least(1))
+                    .or_not(),
+            )
+            .then(closed(ctrl('{').ignore_then(expr.clone()), '}'))
+            .map_with_span(
+                |(((kind, ranges), conditions), body), span| Expr::Generator {
+                    kind,
+                    ranges,
+                    conditions: conditions.unwrap_or_default(),
+                    body: Box::new(body),
                     span,
+                },
+            );
+
+        let parens = closed(ctrl('(').ignore_then(expr.clone()), ')');
+
+        let atom = choice((
+            literal,
+            storage,
+            // Before paths, as a path to a macro would parse as an unfinished path.
+            macro_call,
+            path_expr,
+            intrinsic_call,
+            array,
+            tuple,
+            cond,
+            match_,
+            generator,
+            parens,
+        ))
+        .boxed();
+
+        // `x[i]`, `x.0`, `x.name`, `x'`. `x.0.1` lexes its indices as the real `0.1`.
+        let postfix = choice((
+            closed(ctrl('[').ignore_then(expr.clone().or_not()), ']')
+                .map(|index| vec![Postfix::Index(index)]),
+            ctrl('.')
+                .ignore_then(
+                    select! {
+                        Token::Ident(name) => vec![name],
+                        Token::Int(index) => vec![index],
+                        Token::Real(indices) => indices.split('.').map(str::to_string).collect(),
+                    }
+                    .map_with_span(|names, span: Span| {
+                        let mut start = span.start;
+                        names
+                            .into_iter()
+                            .map(|name| {
+                                let ident = Ident {
+                                    span: start..start + name.len(),
+                                    name,
+                                };
+                                start = ident.span.end + 1;
+                                Postfix::Field(Some(ident))
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .or_not(),
                 )
+                .map(|fields| fields.unwrap_or_else(|| vec![Postfix::Field(None)])),
+            ctrl('\'').to(vec![Postfix::NextState]),
+        ))
+        .map_with_span(|ops, span| (ops, span));
+
+        let postfixed = atom
+            .then(postfix.repeated())
+            .foldl(|expr, (ops, span)| {
+                ops.into_iter().fold(expr, |expr, op| {
+                    let span = expr.span().start..span.end;
+                    let expr = Box::new(expr);
+                    match op {
+                        Postfix::Index(index) => Expr::Index {
+                            expr,
+                            index: index.map(Box::new),
+                            span,
+                        },
+                        Postfix::Field(field) => Expr::Field { expr, field, span },
+                        Postfix::NextState => Expr::NextState { expr, span },
+                    }
+                })
             })
+            .boxed();
+
+        let unary = choice((
+            op("-").to(UnaryOp::Neg),
+            op("!").to(UnaryOp::Not),
+            op("+").to(UnaryOp::Plus),
+        ))
+        .map_with_span(|op, span| (op, span))
+        .repeated()
+        .then(postfixed)
+        .foldr(|(op, span), expr| {
+            let span = span.start..expr.span().end;
+            Expr::Unary {
+                op,
+                expr: Box::new(expr),
+                span,
+            }
+        })
+        .boxed();
+
+        let cast = unary
+            .then(kw("as").ignore_then(ty).repeated())
+            .foldl(|value, ty| {
+                let span = value.span().start..ty.span().end;
+                Expr::Cast {
+                    value: Box::new(value),
+                    ty,
+                    span,
+                }
+            })
+            .boxed();
+
+        let product = binary(
+            cast,
+            choice((
+                op("*").to(BinaryOp::Mul),
+                op("/").to(BinaryOp::Div),
+                op("%").to(BinaryOp::Mod),
+            )),
+        );
+        let sum = binary(
+            product,
+            op("+").to(BinaryOp::Add).or(op("-").to(BinaryOp::Sub)),
+        );
+
+        let in_ = sum
+            .clone()
+            .then(
+                kw("in")
+                    .ignore_then(sum.clone().then(op("..").ignore_then(sum.clone()).or_not()))
+                    .repeated(),
+            )
+            .foldl(|value, (collection, ub)| {
+                let collection = match ub {
+                    Some(ub) => Expr::Range {
+                        span: collection.span().start..ub.span().end,
+                        lb: Box::new(collection),
+                        ub: Box::new(ub),
+                    },
+                    None => collection,
+                };
+                let span = value.span().start..collection.span().end;
+                Expr::In {
+                    value: Box::new(value),
+                    collection: Box::new(collection),
+                    span,
+                }
+            })
+            .boxed();
+
+        let comparison = binary(
+            in_,
+            choice((
+                op("==").to(BinaryOp::Eq),
+                op("!=").to(BinaryOp::NotEq),
+                op("<=").to(BinaryOp::LtEq),
+                op(">=").to(BinaryOp::GtEq),
+                op("<").to(BinaryOp::Lt),
+                op(">").to(BinaryOp::Gt),
+            )),
+        );
+        let and = binary(comparison, op("&&").to(BinaryOp::And));
+        let or = binary(and, op("||").to(BinaryOp::Or));
+
+        or.then(
+            ctrl('?')
+                .ignore_then(expr.clone())
+                .then_ignore(ctrl(':'))
+                .then(expr)
+                .or_not(),
+        )
+        .map_with_span(|(condition, select), span| match select {
+            Some((then_expr, else_expr)) => Expr::Select {
+                condition: Box::new(condition),
+                then_expr: Box::new(then_expr),
+                else_expr: Box::new(else_expr),
+                span,
+            },
+            None => condition,
+        })
+        .boxed()
     })
 }
 
-pub fn funcs_parser() -> impl Parser<Token, HashMap<String, Func>, Error = Simple<Token>> + Clone {
-    let ident = filter_map(|span, tok| match tok {
-        Token::Ident(ident) => Ok(ident),
-        _ => Err(Simple::expected_input_found(span, Vec::new(), Some(tok))),
-    });
+/// Left associative `operand (op operand)*`.
+fn binary(
+    operand: impl Parser<Token, Expr, Error = Simple<Token>> + Clone + 'static,
+    op: impl Parser<Token, BinaryOp, Error = Simple<Token>> + Clone + 'static,
+) -> BoxedParser<'static, Token, Expr, Simple<Token>> {
+    operand
+        .clone()
+        .then(op.then(operand).repeated())
+        .foldl(|lhs, (op, rhs)| {
+            let span = lhs.span().start..rhs.span().end;
+            Expr::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+                span,
+            }
+        })
+        .boxed()
+}
 
-    // Argument lists are just identifiers separated by commas, surrounded by parentheses
-    let args = ident
-        .map_with_span(|name, span| (name, span))
-        .separated_by(just(Token::Ctrl(',')))
-        .allow_trailing()
-        .delimited_by(just(Token::Ctrl('(')), just(Token::Ctrl(')')))
-        .labelled("function args");
+/// `lb..ub`, as used by generators. Expressions never consume `..`, so the bounds stop at it.
+fn expr_range(
+    expr: Recursive<'static, Token, Expr, Simple<Token>>,
+) -> impl Parser<Token, Expr, Error = Simple<Token>> + Clone {
+    expr.clone()
+        .then(op("..").ignore_then(expr).or_not())
+        .map_with_span(|(lb, ub), span| match ub {
+            Some(ub) => Expr::Range {
+                lb: Box::new(lb),
+                ub: Box::new(ub),
+                span,
+            },
+            None => lb,
+        })
+}
 
-    let func = just(Token::Fn)
-        .ignore_then(
-            ident
-                .map_with_span(|name, span| (name, span))
-                .labelled("function name"),
+fn macro_call_parser(
+    expr: impl Parser<Token, Expr, Error = Simple<Token>> + Clone + 'static,
+) -> impl Parser<Token, MacroCall, Error = Simple<Token>> + Clone {
+    // Macro arguments are token soup to pintc and only become code once spliced in, so an
+    // argument that is not an expression is kept as an error node rather than failing the call.
+    let junk = none_of([Token::Ctrl(';'), Token::Ctrl(')')])
+        .repeated()
+        .at_least(1)
+        .map_with_span(|_, span| Expr::Error(span));
+    let arg = choice((
+        expr.then_ignore(none_of([Token::Ctrl(';'), Token::Ctrl(')')]).repeated()),
+        junk,
+    ));
+
+    op("::")
+        .or_not()
+        .then(ident().then_ignore(op("::")).repeated())
+        .then(select! { Token::MacroName(name) => name }.map_with_span(|name, span| (name, span)))
+        .then(closed(
+            ctrl('(').ignore_then(arg.separated_by(ctrl(';')).allow_trailing()),
+            ')',
+        ))
+        .map_with_span(|(((absolute, mut segments), (name, name_span)), args), span: Span| {
+            segments.push(Ident {
+                name,
+                span: name_span.clone(),
+            });
+            MacroCall {
+                name: Path {
+                    absolute: absolute.is_some(),
+                    span: span.start..name_span.end,
+                    segments,
+                    incomplete: false,
+                },
+                name_span,
+                args,
+                span,
+            }
+        })
+}
+
+fn use_tree() -> impl Parser<Token, UseTree, Error = Simple<Token>> + Clone {
+    recursive(|tree| {
+        let name = ident()
+            .or(select! { Token::MacroName(name) => name }
+                .map_with_span(|name, span| Ident { name, span }))
+            .or(kw("self").map_with_span(|_, span| Ident {
+                name: "self".to_string(),
+                span,
+            }));
+        let group = list(tree.clone(), '{', '}').map(UseTree::Group);
+        let path = name
+            .clone()
+            .then_ignore(op("::"))
+            .then(tree)
+            .map(|(prefix, suffix)| UseTree::Path {
+                prefix,
+                suffix: Box::new(suffix),
+            });
+        let alias = name
+            .clone()
+            .then_ignore(kw("as"))
+            .then(ident())
+            .map(|(name, alias)| UseTree::Alias { name, alias });
+        choice((group, path, alias, name.map(UseTree::Name)))
+    })
+}
+
+fn decls_parser() -> impl Parser<Token, Ast, Error = Simple<Token>> + Clone {
+    let expr = expr_parser();
+    let ty = type_parser(expr.clone());
+
+    // Declarations end in `;`. A missing one is reported but the declaration is kept.
+    fn terminated<O>(
+        item: impl Parser<Token, O, Error = Simple<Token>> + Clone,
+    ) -> impl Parser<Token, O, Error = Simple<Token>> + Clone {
+        closed(item, ';')
+    }
+
+    let decl = recursive(|decl: Recursive<Token, Decl, Simple<Token>>| {
+        // Anything that is not a declaration is skipped one token at a time, up to the `}` that
+        // closes the enclosing block.
+        let stray = none_of([Token::Ctrl('}')]).validate(|token, span: Span, emit| {
+            emit(Simple::custom(
+                span.clone(),
+                format!("unexpected `{}`", token),
+            ));
+            Decl::Error(span)
+        });
+        let block = closed(
+            ctrl('{').ignore_then(
+                choice((
+                    decl.clone(),
+                    expr.clone().then_ignore(ctrl(';').or_not()).map(Decl::Expr),
+                    stray,
+                ))
+                .repeated(),
+            ),
+            '}',
         )
-        .then(args)
-        .then(
-            expr_parser()
-                .delimited_by(just(Token::Ctrl('{')), just(Token::Ctrl('}')))
-                // Attempt to recover anything that looks like a function body but contains errors
-                .recover_with(nested_delimiters(
-                    Token::Ctrl('{'),
-                    Token::Ctrl('}'),
-                    [
-                        (Token::Ctrl('('), Token::Ctrl(')')),
-                        (Token::Ctrl('['), Token::Ctrl(']')),
-                    ],
-                    |span| (Expr::Error, span),
-                )),
+        .boxed();
+
+        let param = ident()
+            .then(ctrl(':').ignore_then(ty.clone().or_not()).or_not())
+            .map_with_span(|(name, ty), span| Param {
+                name,
+                ty: ty.flatten(),
+                span,
+            });
+        let params = list(param, '(', ')');
+
+        let storage_var = ident()
+            .then(ctrl(':').ignore_then(ty.clone().or_not()).or_not())
+            .map_with_span(|(name, ty), span| StorageVar {
+                name,
+                ty: ty.flatten(),
+                span,
+            });
+        let storage_block = kw("storage").ignore_then(list(storage_var, '{', '}'));
+
+        let let_ = terminated(
+            kw("let")
+                .ignore_then(ident())
+                .then(ctrl(':').ignore_then(ty.clone()).or_not())
+                .then(op("=").ignore_then(expr.clone().or_not()).or_not()),
         )
-        .map_with_span(|((name, args), body), span| {
-            (
-                name.clone(),
-                Func {
-                    args,
-                    body,
+        .map_with_span(|((name, ty), init), span| Decl::Let {
+            name,
+            ty,
+            init: init.flatten(),
+            span,
+        });
+
+        let constraint = terminated(kw("constraint").ignore_then(expr.clone().or_not()))
+            .map_with_span(|expr, span: Span| Decl::Constraint {
+                expr: expr.unwrap_or(Expr::Error(span.end..span.end)),
+                span,
+            });
+
+        let const_ = terminated(
+            kw("const")
+                .ignore_then(ident())
+                .then(ctrl(':').ignore_then(ty.clone()).or_not())
+                .then(op("=").ignore_then(expr.clone().or_not()).or_not()),
+        )
+        .map_with_span(|((name, ty), init), span| Decl::Const {
+            name,
+            ty,
+            init: init.flatten(),
+            span,
+        });
+
+        let new_type = terminated(
+            kw("type")
+                .ignore_then(ident())
+                .then(op("=").ignore_then(ty.clone().or_not()).or_not()),
+        )
+        .map_with_span(|(name, ty), span| Decl::NewType {
+            name,
+            ty: ty.flatten(),
+            span,
+        });
+
+        let variant = ident()
+            .then(closed(ctrl('(').ignore_then(ty.clone()), ')').or_not())
+            .map(|(name, ty)| UnionVariant { name, ty });
+        let union = terminated(
+            kw("union").ignore_then(ident()).then(
+                op("=")
+                    .ignore_then(variant.separated_by(ctrl('|')))
+                    .or_not(),
+            ),
+        )
+        .map_with_span(|(name, variants), span| Decl::Union {
+            name,
+            variants: variants.unwrap_or_default(),
+            span,
+        });
+
+        let storage = storage_block
+            .clone()
+            .map_with_span(|vars, span| Decl::Storage { vars, span });
+
+        let predicate_interface =
+            terminated(kw("predicate").ignore_then(ident()).then(params.clone()))
+                .map_with_span(|(name, params), span| PredicateInterface { name, params, span });
+        let interface = kw("interface")
+            .ignore_then(ident())
+            .then(closed(
+                ctrl('{').ignore_then(
+                    choice((storage_block.map(Ok), predicate_interface.map(Err))).repeated(),
+                ),
+                '}',
+            ))
+            .map_with_span(|(name, items), span| {
+                let mut storage = vec![];
+                let mut predicates = vec![];
+                for item in items {
+                    match item {
+                        Ok(vars) => storage.extend(vars),
+                        Err(predicate) => predicates.push(predicate),
+                    }
+                }
+                Decl::Interface {
                     name,
+                    storage,
+                    predicates,
                     span,
-                },
-            )
-        })
-        .labelled("function");
-
-    func.repeated()
-        .try_map(|fs, _| {
-            let mut funcs = HashMap::new();
-            for ((name, name_span), f) in fs {
-                if funcs.insert(name.clone(), f).is_some() {
-                    return Err(Simple::custom(
-                        name_span,
-                        format!("Function '{}' already exists", name),
-                    ));
                 }
-            }
-            Ok(funcs)
-        })
-        .then_ignore(end())
-}
+            });
 
-pub fn type_inference(expr: &Spanned<Expr>, symbol_type_table: &mut HashMap<Span, Value>) {
-    match &expr.0 {
-        Expr::Error => {}
-        Expr::Value(_) => {}
-        Expr::List(exprs) => exprs
-            .iter()
-            .for_each(|expr| type_inference(expr, symbol_type_table)),
-        Expr::Local(_) => {}
-        Expr::Let(_name, lhs, rest, name_span) => {
-            if let Some(value) = lhs.0.as_value() {
-                symbol_type_table.insert(name_span.clone(), value.clone());
-            }
-            type_inference(rest, symbol_type_table);
-        }
-        Expr::Then(first, second) => {
-            type_inference(first, symbol_type_table);
-            type_inference(second, symbol_type_table);
-        }
-        Expr::Binary(_, _, _) => {}
-        Expr::Call(_, _) => {}
-        Expr::If(_test, consequent, alternative) => {
-            type_inference(consequent, symbol_type_table);
-            type_inference(alternative, symbol_type_table);
-        }
-        Expr::Print(expr) => {
-            type_inference(expr, symbol_type_table);
+        let predicate = kw("predicate")
+            .ignore_then(ident())
+            .then(params.or_not())
+            .then(block.clone().or_not())
+            .map_with_span(|((name, params), body), span| Decl::Predicate {
+                name,
+                params: params.unwrap_or_default(),
+                body: body.unwrap_or_default(),
+                span,
+            });
+
+        let macro_param = select! {
+            Token::MacroParam(name) => (name, false),
+            Token::MacroPack(name) => (name, true),
         }
-    }
+        .map_with_span(|(name, pack), span| (Ident { name, span }, pack));
+        let macro_ = kw("macro")
+            .ignore_then(
+                select! { Token::MacroName(name) => name }
+                    .map_with_span(|name, span| Ident { name, span }),
+            )
+            .then(list(macro_param, '(', ')'))
+            .then(block.clone())
+            .map_with_span(|((name, params), body), span| {
+                let (packs, params): (Vec<_>, Vec<_>) =
+                    params.into_iter().partition(|(_, pack)| *pack);
+                Decl::Macro {
+                    name,
+                    params: params.into_iter().map(|(param, _)| param).collect(),
+                    pack: packs.into_iter().next().map(|(pack, _)| pack),
+                    body,
+                    span,
+                }
+            });
+
+        let use_ = terminated(kw("use").ignore_then(op("::").or_not()).then(use_tree()))
+            .map_with_span(|(absolute, tree), span| Decl::Use {
+                absolute: absolute.is_some(),
+                tree,
+                span,
+            });
+
+        let if_ = recursive(|if_: Recursive<Token, Decl, Simple<Token>>| {
+            kw("if")
+                .ignore_then(expr.clone())
+                .then(block.clone())
+                .then(
+                    kw("else")
+                        .ignore_then(choice((block.clone(), if_.map(|if_| vec![if_]))))
+                        .or_not(),
+                )
+                .map_with_span(|((condition, then_block), else_block), span| Decl::If {
+                    condition,
+                    then_block,
+                    else_block,
+                    span,
+                })
+        });
+
+        let match_arm = path()
+            .then(closed(ctrl('(').ignore_then(ident()), ')').or_not())
+            .then_ignore(op("=>"))
+            .then(block.clone())
+            .then_ignore(ctrl(',').or_not())
+            .map(|((pattern, binding), body)| MatchDeclArm {
+                pattern,
+                binding,
+                body,
+            });
+        let match_ = kw("match")
+            .ignore_then(expr.clone())
+            .then(closed(
+                ctrl('{').ignore_then(match_arm.repeated()).then(
+                    kw("else")
+                        .ignore_then(op("=>"))
+                        .ignore_then(block)
+                        .then_ignore(ctrl(',').or_not())
+                        .or_not(),
+                ),
+                '}',
+            ))
+            .map_with_span(|(expr, (arms, else_block)), span| Decl::Match {
+                expr,
+                arms,
+                else_block,
+                span,
+            });
+
+        choice((
+            let_, constraint, const_, new_type, union, storage, interface, predicate, macro_, use_,
+            if_, match_,
+        ))
+        .boxed()
+    });
+
+    let stray = any().validate(|token, span: Span, emit| {
+        emit(Simple::custom(
+            span.clone(),
+            format!("unexpected `{}`", token),
+        ));
+        Decl::Error(span)
+    });
+
+    choice((
+        decl,
+        expr.then_ignore(ctrl(';').or_not()).map(Decl::Expr),
+        stray,
+    ))
+    .repeated()
+    .then_ignore(end())
 }
 
 #[derive(Debug)]
 pub struct ParserResult {
-    pub ast: Option<HashMap<String, Func>>,
+    pub ast: Option<Ast>,
     pub parse_errors: Vec<Simple<String>>,
     pub semantic_tokens: Vec<ImCompleteSemanticToken>,
+    /// Every `//` comment with its text after the slashes.
+    pub comments: Vec<Spanned<String>>,
 }
 
 pub fn parse(src: &str) -> ParserResult {
-    let (tokens, errs) = lexer().parse_recovery(src);
+    let len = src.len();
+    let chars = Stream::from_iter(
+        len..len,
+        src.char_indices()
+            .map(|(offset, c)| (c, offset..offset + c.len_utf8())),
+    );
+    let (tokens, errs) = lexer().parse_recovery(chars);
 
-    let (ast, tokenize_errors, semantic_tokens) = if let Some(tokens) = tokens {
-        // info!("Tokens = {:?}", tokens);
+    let (ast, parse_errs, semantic_tokens, comments) = if let Some(tokens) = tokens {
         let semantic_tokens = tokens
             .iter()
-            .filter_map(|(token, span)| match token {
-                Token::Null => None,
-                Token::Bool(_) => None,
-
-                Token::Num(_) => Some(ImCompleteSemanticToken {
+            .filter_map(|(token, span)| {
+                let ty = match token {
+                    Token::Int(n) if is_b256(n) => B256,
+                    Token::Int(_) | Token::Real(_) => SemanticTokenType::NUMBER,
+                    Token::Str(_) => SemanticTokenType::STRING,
+                    Token::Kw(kw) if PRIMITIVE_TYPES.contains(kw) => {
+                        SemanticTokenType::TYPE
+                    }
+                    Token::Kw(_) => SemanticTokenType::KEYWORD,
+                    Token::Op(_) => SemanticTokenType::OPERATOR,
+                    Token::Comment(_) => SemanticTokenType::COMMENT,
+                    Token::Intrinsic(_) => SemanticTokenType::FUNCTION,
+                    Token::MacroName(_) => SemanticTokenType::MACRO,
+                    Token::MacroParam(_) | Token::MacroPack(_) => SemanticTokenType::PARAMETER,
+                    Token::Ident(_) | Token::Ctrl(_) => return None,
+                };
+                Some(ImCompleteSemanticToken {
                     start: span.start,
                     length: span.len(),
-                    token_type: LEGEND_TYPE
-                        .iter()
-                        .position(|item| item == &SemanticTokenType::NUMBER)
-                        .unwrap(),
-                }),
-                Token::Str(_) => Some(ImCompleteSemanticToken {
-                    start: span.start,
-                    length: span.len(),
-                    token_type: LEGEND_TYPE
-                        .iter()
-                        .position(|item| item == &SemanticTokenType::STRING)
-                        .unwrap(),
-                }),
-                Token::Op(_) => Some(ImCompleteSemanticToken {
-                    start: span.start,
-                    length: span.len(),
-                    token_type: LEGEND_TYPE
-                        .iter()
-                        .position(|item| item == &SemanticTokenType::OPERATOR)
-                        .unwrap(),
-                }),
-                Token::Ctrl(_) => None,
-                Token::Ident(_) => None,
-                Token::Fn => Some(ImCompleteSemanticToken {
-                    start: span.start,
-                    length: span.len(),
-                    token_type: LEGEND_TYPE
-                        .iter()
-                        .position(|item| item == &SemanticTokenType::KEYWORD)
-                        .unwrap(),
-                }),
-                Token::Let => Some(ImCompleteSemanticToken {
-                    start: span.start,
-                    length: span.len(),
-                    token_type: LEGEND_TYPE
-                        .iter()
-                        .position(|item| item == &SemanticTokenType::KEYWORD)
-                        .unwrap(),
-                }),
-                Token::Print => Some(ImCompleteSemanticToken {
-                    start: span.start,
-                    length: span.len(),
-                    token_type: LEGEND_TYPE
-                        .iter()
-                        .position(|item| item == &SemanticTokenType::FUNCTION)
-                        .unwrap(),
-                }),
-                Token::If => Some(ImCompleteSemanticToken {
-                    start: span.start,
-                    length: span.len(),
-                    token_type: LEGEND_TYPE
-                        .iter()
-                        .position(|item| item == &SemanticTokenType::KEYWORD)
-                        .unwrap(),
-                }),
-                Token::Else => Some(ImCompleteSemanticToken {
-                    start: span.start,
-                    length: span.len(),
-                    token_type: LEGEND_TYPE
-                        .iter()
-                        .position(|item| item == &SemanticTokenType::KEYWORD)
-                        .unwrap(),
-                }),
+                    token_type: legend_index(&ty),
+                    modifiers: 0,
+                })
             })
             .collect::<Vec<_>>();
-        let len = src.chars().count();
+
+        let (comments, tokens): (Vec<_>, Vec<_>) = tokens
+            .into_iter()
+            .partition(|(token, _)| matches!(token, Token::Comment(_)));
+        let comments = comments
+            .into_iter()
+            .filter_map(|(token, span)| match token {
+                Token::Comment(text) => Some((text, span)),
+                _ => None,
+            })
+            .collect();
+
         let (ast, parse_errs) =
-            funcs_parser().parse_recovery(Stream::from_iter(len..len + 1, tokens.into_iter()));
+            decls_parser().parse_recovery(Stream::from_iter(len..len + 1, tokens.into_iter()));
 
-        (ast, parse_errs, semantic_tokens)
+        (ast, parse_errs, semantic_tokens, comments)
     } else {
-        (None, Vec::new(), vec![])
+        (None, Vec::new(), vec![], vec![])
     };
 
     let parse_errors = errs
         .into_iter()
         .map(|e| e.map(|c| c.to_string()))
-        .chain(
-            tokenize_errors
-                .into_iter()
-                .map(|e| e.map(|tok| tok.to_string())),
-        )
+        .chain(parse_errs.into_iter().map(|e| e.map(|tok| tok.to_string())))
         .collect::<Vec<_>>();
 
     ParserResult {
         ast,
         parse_errors,
         semantic_tokens,
+        comments,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every declaration in `ast`, nested ones included, outermost first.
+    fn all_decls(ast: &Ast) -> Vec<&Decl> {
+        fn collect<'a>(decl: &'a Decl, decls: &mut Vec<&'a Decl>) {
+            decls.push(decl);
+            for child in decl.children() {
+                collect(child, decls);
+            }
+        }
+        let mut decls = vec![];
+        for decl in ast {
+            collect(decl, &mut decls);
+        }
+        decls
+    }
+
+    fn names(ast: &Ast) -> Vec<&str> {
+        all_decls(ast)
+            .into_iter()
+            .filter_map(|decl| match decl {
+                Decl::Const { name, .. }
+                | Decl::Predicate { name, .. }
+                | Decl::Let { name, .. } => Some(name.name.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn storage_access_without_a_name() {
+        let result = parse("predicate P() {\n    let x: int = storage::\n}\n");
+        let ast = result.ast.expect("a syntax tree");
+        let init = all_decls(&ast).into_iter().find_map(|decl| match decl {
+            Decl::Let { name, init, .. } if name.name == "x" => init.as_ref(),
+            _ => None,
+        });
+        assert!(
+            matches!(init, Some(Expr::Storage { name: None, .. })),
+            "{init:?}"
+        );
+    }
+
+    #[test]
+    fn field_access_without_a_field() {
+        let result = parse("predicate P() {\n    constraint t.\n}\n");
+        let ast = result.ast.expect("a syntax tree");
+        let expr = all_decls(&ast).into_iter().find_map(|decl| match decl {
+            Decl::Constraint { expr, .. } => Some(expr),
+            _ => None,
+        });
+        let Some(Expr::Field {
+            expr, field: None, ..
+        }) = expr
+        else {
+            panic!("{expr:?}");
+        };
+        assert!(matches!(&**expr, Expr::Path(path) if path.segments[0].name == "t"));
+    }
+
+    #[test]
+    fn tuple_type_field_without_a_type() {
+        let result = parse("type T = { a: int, b: };\n");
+        let ast = result.ast.expect("a syntax tree");
+        let Some(Decl::NewType {
+            ty: Some(Type::Tuple(fields, _)),
+            ..
+        }) = ast.first()
+        else {
+            panic!("{ast:?}");
+        };
+        let names: Vec<_> = fields
+            .iter()
+            .map(|(name, ty)| (name.as_ref().map(|name| name.name.as_str()), ty))
+            .collect();
+        assert!(
+            matches!(
+                names[..],
+                [
+                    (Some("a"), Type::Primitive(..)),
+                    (Some("b"), Type::Error(_))
+                ]
+            ),
+            "{names:?}"
+        );
+    }
+
+    #[test]
+    fn unclosed_block() {
+        let result = parse("const A: int = 1;\npredicate P() {\n    let x = 1;\n");
+        assert!(!result.parse_errors.is_empty());
+        let ast = result.ast.expect("a syntax tree");
+        assert_eq!(names(&ast), ["A", "P", "x"]);
+    }
+
+    #[test]
+    fn recovers_the_declarations_after_a_broken_one() {
+        let result = parse("const = 1 2;\nconst B: int = 2;\npredicate P() {\n}\n");
+        assert!(!result.parse_errors.is_empty());
+        let ast = result.ast.expect("a syntax tree");
+        assert_eq!(names(&ast), ["B", "P"]);
+    }
+
+    #[test]
+    fn spans_are_byte_offsets() {
+        let src = "// héllo wörld\nconst A: string = \"ü\";\nconst B: int = 1;\n";
+        let result = parse(src);
+        let ast = result.ast.expect("a syntax tree");
+        let spans: Vec<_> = ast
+            .iter()
+            .filter_map(|decl| match decl {
+                Decl::Const { name, .. } => Some(name.span.clone()),
+                _ => None,
+            })
+            .collect();
+        let a = src.find("A:").unwrap();
+        let b = src.find("B:").unwrap();
+        assert_eq!(spans, [a..a + 1, b..b + 1]);
+        let comment = &result.comments[0];
+        assert_eq!(comment.1, 0..src.find('\n').unwrap());
     }
 }
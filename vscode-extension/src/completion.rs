@@ -1,66 +1,549 @@
-use std::collections::HashMap;
+use std::path::Path;
+
+use pintc::expr::Expr as PintExpr;
 use pintc::predicate::Contract;
+use serde::{Deserialize, Serialize};
+use tower_lsp::lsp_types::{CompletionItemKind, Url};
+
+use crate::chumsky::{Ast, Decl, Ident, Span, Spanned, Type, PRIMITIVE_TYPES};
+use crate::hover::local_info;
+use crate::intrinsics::{Intrinsic, INTRINSICS};
+use crate::jump_definition::{contains, locals, visit_contract};
+use crate::snapshot::Snapshot;
+use crate::snippet::{placement_at, Placement, Snippet};
 
 pub enum ImCompleteCompletionItem {
-    Variable(String),
+    /// A parameter, `let` or generator index, with its type when pintc knows it.
+    Variable {
+        name: String,
+        ty: Option<String>,
+    },
     StorageVariable(String),
+    Intrinsic(&'static Intrinsic),
+    /// A template for a declaration, statement or expression.
+    Snippet(&'static Snippet),
     Type(String),
     Keyword(String),
+    /// A name reached through a path or a `.`, such as a storage field or a union variant.
+    Member {
+        name: String,
+        kind: CompletionItemKind,
+        detail: Option<String>,
+    },
 }
 
+/// What a completion item names, for completionItem/resolve to look its declaration up with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CompletionData {
+    /// A name reached through a path as written in `uri`, e.g. `storage::counter` or `lib::N`.
+    Path {
+        uri: Url,
+        absolute: bool,
+        segments: Vec<String>,
+    },
+    /// A parameter or `let` named `name`, in scope at `offset` in `uri`.
+    Local {
+        uri: Url,
+        offset: usize,
+        name: String,
+    },
+}
 
-pub fn completion(
-    ast: &Contract,
-    ident_offset: usize,
-) -> HashMap<String, ImCompleteCompletionItem> {
-    let mut map = HashMap::new();
-    
-    let arr = ["int", "bool", "b256"];
-    
-    arr.iter().for_each(|item| {
-        map.insert(
-            item.to_string(),
-            ImCompleteCompletionItem::Type(item.to_string()));
-    });
+/// What completionItem/resolve adds to an item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Details {
+    /// The declared type, if the declaration has one.
+    pub ty: Option<String>,
+    /// The line of the declaration, trimmed.
+    pub source: String,
+    /// The comments on the lines right above the declaration.
+    pub doc: Option<String>,
+    /// The index of a storage field, which is the first word of its storage keys.
+    pub storage_key: Option<usize>,
+}
 
-    let arr = ["let", "mut", "predicate", "constraint"];
-    
-    arr.iter().for_each(|item| {
-        map.insert(
-            item.to_string(),
-            ImCompleteCompletionItem::Keyword(item.to_string()));
-    });
+/// What the text before the cursor asks to be completed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompletionContext {
+    /// A plain name.
+    Name,
+    /// A type, after the `:` that follows a name being declared.
+    Type,
+    /// The last segment of a path, after `a::b::`.
+    Path {
+        absolute: bool,
+        segments: Vec<String>,
+    },
+    /// A field, after the `.` ending at `receiver_end`.
+    Member { receiver_end: usize },
+    /// Nothing, such as after the `..` of a range.
+    None,
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '@' | '$')
+}
+
+/// The completion context at `offset` in `text`, going by the characters before the word being
+/// typed. This works on half typed code that does not parse. `ast` is the syntax tree of `text`,
+/// which tells a `:` before a type from the others.
+pub fn completion_context(ast: &Ast, text: &str, offset: usize) -> CompletionContext {
+    let Some(before) = text.get(..offset) else {
+        return CompletionContext::None;
+    };
+    let head = before.trim_end_matches(is_ident_char);
+
+    if let Some(mut rest) = head.strip_suffix("::") {
+        let mut segments = vec![];
+        let absolute = loop {
+            let segment_start = rest.trim_end_matches(is_ident_char).len();
+            if segment_start == rest.len() {
+                // Nothing before the `::`, so the path starts at the root module.
+                break true;
+            }
+            segments.push(rest[segment_start..].to_string());
+            match rest[..segment_start].strip_suffix("::") {
+                Some(prefix) => rest = prefix,
+                None => break false,
+            }
+        };
+        segments.reverse();
+        return CompletionContext::Path { absolute, segments };
+    }
+    if head.ends_with("..") {
+        return CompletionContext::None;
+    }
+    if let Some(receiver) = head.strip_suffix('.') {
+        return CompletionContext::Member {
+            receiver_end: receiver.len(),
+        };
+    }
+    if let Some(name) = head.trim_end().strip_suffix(':') {
+        let name_end = name.trim_end().len();
+        if annotated_names(ast)
+            .iter()
+            .any(|ident| ident.span.end == name_end)
+        {
+            return CompletionContext::Type;
+        }
+    }
+    CompletionContext::Name
+}
 
+/// The names in `ast` that a `:` and their type follow: those of `let`s, consts, parameters,
+/// storage fields and the named fields of tuple types.
+fn annotated_names(ast: &Ast) -> Vec<&Ident> {
+    fn field_names<'a>(ty: &'a Type, names: &mut Vec<&'a Ident>) {
+        match ty {
+            Type::Error(_) | Type::Primitive(..) | Type::Custom(_) => {}
+            Type::Tuple(fields, _) => {
+                for (name, ty) in fields {
+                    names.extend(name);
+                    field_names(ty, names);
+                }
+            }
+            Type::Array { ty, .. } | Type::Vector { ty, .. } => field_names(ty, names),
+            Type::Map { from, to, .. } => {
+                field_names(from, names);
+                field_names(to, names);
+            }
+        }
+    }
 
-    ast.storage.iter().for_each(|item| {
-        let (storage_vars, span) = item;
-        storage_vars.iter().for_each(|var| {    
-            map.insert(
-                var.name.to_string(),
-                ImCompleteCompletionItem::StorageVariable(format!("{}{}", "storage::".to_string(), var.name.to_string())),
-            );
+    let mut names = vec![];
+    for decl in ast {
+        decl.walk(&mut |decl| {
+            match decl {
+                Decl::Const { name, .. } | Decl::Let { name, .. } => names.push(name),
+                Decl::Predicate { params, .. } => {
+                    names.extend(params.iter().map(|param| &param.name))
+                }
+                Decl::Storage { vars, .. } => names.extend(vars.iter().map(|var| &var.name)),
+                Decl::Interface {
+                    storage,
+                    predicates,
+                    ..
+                } => {
+                    names.extend(storage.iter().map(|var| &var.name));
+                    names.extend(
+                        predicates
+                            .iter()
+                            .flat_map(|predicate| &predicate.params)
+                            .map(|param| &param.name),
+                    );
+                }
+                _ => {}
+            }
+            for ty in decl.types() {
+                field_names(ty, &mut names);
+            }
         });
+    }
+    names
+}
+
+/// The built-in types and the types declared in `ast`, for after a `:`.
+pub fn type_completion(ast: &Ast) -> Vec<ImCompleteCompletionItem> {
+    let mut items = PRIMITIVE_TYPES
+        .iter()
+        .map(|ty| ImCompleteCompletionItem::Type(ty.to_string()))
+        .collect::<Vec<_>>();
+    for decl in ast {
+        match decl {
+            Decl::NewType { name, .. } | Decl::Union { name, .. } => {
+                items.push(ImCompleteCompletionItem::Type(name.name.clone()))
+            }
+            _ => {}
+        }
+    }
+    items
+}
+
+/// The keywords that fit at `offset` in `text`, of which `ast` is the syntax tree. Those that
+/// start a declaration or a statement are offered as snippets instead.
+pub fn keywords_at(ast: &Ast, text: &str, offset: usize) -> Vec<ImCompleteCompletionItem> {
+    match placement_at(ast, text, offset) {
+        // `mut storage::x`
+        Placement::Expression => vec![ImCompleteCompletionItem::Keyword("mut".to_string())],
+        _ => vec![],
+    }
+}
+
+/// The fields of the `storage` block in `ast`.
+pub fn storage_fields(ast: &Ast) -> Vec<ImCompleteCompletionItem> {
+    ast.iter()
+        .filter_map(|decl| match decl {
+            Decl::Storage { vars, .. } => Some(vars),
+            _ => None,
+        })
+        .flatten()
+        .map(|var| ImCompleteCompletionItem::Member {
+            name: var.name.name.clone(),
+            kind: CompletionItemKind::FIELD,
+            detail: var.ty.as_ref().map(ToString::to_string),
+        })
+        .collect()
+}
+
+/// What a module declares at its top level, with `ast` its syntax tree, and its submodules.
+pub fn module_items(ast: &Ast, submodules: Vec<String>) -> Vec<ImCompleteCompletionItem> {
+    let mut items = vec![];
+    for decl in ast {
+        let (name, kind, detail) = match decl {
+            Decl::Const { name, ty, .. } => (
+                name,
+                CompletionItemKind::CONSTANT,
+                ty.as_ref().map(ToString::to_string),
+            ),
+            Decl::NewType { name, ty, .. } => (
+                name,
+                CompletionItemKind::STRUCT,
+                ty.as_ref().map(ToString::to_string),
+            ),
+            Decl::Union { name, .. } => (name, CompletionItemKind::ENUM, None),
+            Decl::Interface { name, .. } => (name, CompletionItemKind::INTERFACE, None),
+            Decl::Predicate { name, .. } | Decl::Macro { name, .. } => {
+                (name, CompletionItemKind::FUNCTION, None)
+            }
+            _ => continue,
+        };
+        items.push(ImCompleteCompletionItem::Member {
+            name: name.name.clone(),
+            kind,
+            detail,
+        });
+    }
+    items.extend(
+        submodules
+            .into_iter()
+            .map(|name| ImCompleteCompletionItem::Member {
+                name,
+                kind: CompletionItemKind::MODULE,
+                detail: None,
+            }),
+    );
+    items
+}
+
+/// The variants of the union `union` declared in `ast`.
+pub fn union_variants(ast: &Ast, union: &str) -> Vec<ImCompleteCompletionItem> {
+    ast.iter()
+        .find_map(|decl| match decl {
+            Decl::Union { name, variants, .. } if name.name == union => Some(variants),
+            _ => None,
+        })
+        .into_iter()
+        .flatten()
+        .map(|variant| ImCompleteCompletionItem::Member {
+            name: variant.name.name.clone(),
+            kind: CompletionItemKind::ENUM_MEMBER,
+            detail: variant.ty.as_ref().map(ToString::to_string),
+        })
+        .collect()
+}
+
+/// The name that the expression ending at `receiver_end` in `text` consists of, if it is just a
+/// name, such as the `t` of `t.`.
+pub fn receiver_name(text: &str, receiver_end: usize) -> Option<&str> {
+    let before = text.get(..receiver_end)?;
+    let head = before.trim_end_matches(is_ident_char);
+    let name = &before[head.len()..];
+    let plain = !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && !head.ends_with(['.', ':', '\'']);
+    plain.then_some(name)
+}
+
+/// The fields of the tuple that the parameter or `let` named by `name` in `file`, a staged path,
+/// holds. `name` is in the current text of the document `snapshot` is of.
+pub fn local_tuple_fields(
+    snapshot: &Snapshot,
+    file: &Path,
+    name: &Ident,
+) -> Vec<ImCompleteCompletionItem> {
+    local_info(snapshot, file, name)
+        .map(|local| field_items(local.fields))
+        .unwrap_or_default()
+}
+
+/// The fields of the tuple that the expression ending at `receiver_end` in `file` evaluates to.
+/// Offsets are in the text the contract was built from.
+pub fn tuple_fields(
+    contract: &Contract,
+    file: &Path,
+    receiver_end: usize,
+) -> Vec<ImCompleteCompletionItem> {
+    // The innermost expression ending there is the receiver; outer ones like `a + b` only
+    // share its end.
+    let mut receiver = None;
+    visit_contract(contract, |key, _| {
+        let span = contract.expr_key_to_span(key);
+        if span.context().as_ref() != file || span.end() != receiver_end {
+            return;
+        }
+        let len = span.end() - span.start();
+        if receiver.is_none_or(|(best, _)| len < best) {
+            receiver = Some((len, key));
+        }
     });
+    let Some((_, key)) = receiver else {
+        return vec![];
+    };
+    let fields = key
+        .get_ty(contract)
+        .get_tuple_fields()
+        .into_iter()
+        .flatten()
+        .map(|(name, ty)| {
+            (
+                name.as_ref().map(ToString::to_string),
+                contract.with_ctrct(ty).to_string(),
+            )
+        })
+        .collect();
+    field_items(fields)
+}
 
-    ast.preds.iter().for_each(|pred| {
-        // map.insert(
-        //     pred.1.name.to_string(),
-        //     ImCompleteCompletionItem::Variable(pred.1.name.to_string().chars().skip(2).collect()), //TODO: remove dots
-        // );
-        pred.1.params.iter().for_each(|param| {
-            let key_value= pred.1.name.to_string() + "::" + &param.name.to_string();
-            map.insert(
-                key_value,
-                ImCompleteCompletionItem::Variable(param.name.to_string().chars().skip(2).collect()),
-            );
+/// Tuple fields, given by name and type, by name where they have one and by index.
+fn field_items(fields: Vec<(Option<String>, String)>) -> Vec<ImCompleteCompletionItem> {
+    let mut items = vec![];
+    for (index, (name, ty)) in fields.into_iter().enumerate() {
+        if let Some(name) = name {
+            items.push(ImCompleteCompletionItem::Member {
+                name,
+                kind: CompletionItemKind::FIELD,
+                detail: Some(ty.clone()),
+            });
+        }
+        items.push(ImCompleteCompletionItem::Member {
+            name: index.to_string(),
+            kind: CompletionItemKind::FIELD,
+            detail: Some(ty),
         });
+    }
+    items
+}
+
+/// The predicate whose body `offset` is in.
+pub fn enclosing_predicate(ast: &Ast, offset: usize) -> Option<&Ident> {
+    ast.iter().find_map(|decl| match decl {
+        Decl::Predicate { name, span, .. } if contains(span, offset) => Some(name),
+        _ => None,
+    })
+}
+
+/// Where the parameter or `let` named `name` in the declaration around `offset` is declared.
+/// Locals are in scope in the whole of a predicate, before their declaration too.
+pub fn local_declaration(ast: &Ast, offset: usize, name: &str) -> Option<Span> {
+    let decl = ast.iter().find(|decl| contains(&decl.span(), offset))?;
+    locals(decl)
+        .into_iter()
+        .find(|local| local.name == name)
+        .map(|local| local.span.clone())
+}
+
+/// pintc prefixes every name with its module and predicate, e.g. `::lib::x`.
+fn local_name(name: &str) -> &str {
+    name.rsplit("::").next().unwrap_or(name)
+}
 
-        pred.1.variables.variables().into_iter().for_each(|v |{
-            let var_key= pred.1.name.to_string() + "::" + &v.1.name.to_string();
-            map.insert(var_key, ImCompleteCompletionItem::Variable(
-              v.1.name.to_string().chars().skip(2).collect()));
+/// Completions at `ident_offset` in `file`, a staged path, inside the predicate of `contract`
+/// with the full name `predicate` (such as `::lib::Foo`), if any. Offsets are in the text the
+/// contract was built from.
+pub fn completion(
+    contract: &Contract,
+    file: &Path,
+    predicate: Option<&str>,
+    ident_offset: usize,
+) -> Vec<ImCompleteCompletionItem> {
+    let mut items = vec![];
+
+    for ty in PRIMITIVE_TYPES {
+        items.push(ImCompleteCompletionItem::Type(ty.to_string()));
+    }
+    items.extend(INTRINSICS.iter().map(ImCompleteCompletionItem::Intrinsic));
+    if let Some((storage_vars, _)) = &contract.storage {
+        for var in storage_vars {
+            items.push(ImCompleteCompletionItem::StorageVariable(format!(
+                "storage::{}",
+                var.name
+            )));
+        }
+    }
+
+    let Some(pred) =
+        predicate.and_then(|predicate| contract.preds.values().find(|pred| pred.name == predicate))
+    else {
+        return items;
+    };
+    for param in &pred.params {
+        items.push(ImCompleteCompletionItem::Variable {
+            name: local_name(&param.name.to_string()).to_string(),
+            ty: Some(contract.with_ctrct(&param.ty).to_string()),
+        });
+    }
+    for (key, var) in pred.variables.variables() {
+        // A `let` is not in scope in its own initialiser.
+        let own = var.span.context().as_ref() == file
+            && contains(&(var.span.start()..var.span.end()), ident_offset);
+        if own {
+            continue;
+        }
+        let ty = key.get_ty(pred);
+        items.push(ImCompleteCompletionItem::Variable {
+            name: local_name(&var.name).to_string(),
+            ty: (!ty.is_unknown()).then(|| contract.with_ctrct(ty).to_string()),
         });
+    }
+    // Generator indices are only in scope inside their `forall` or `exists`.
+    visit_contract(contract, |_, expr| {
+        if let PintExpr::Generator {
+            gen_ranges, span, ..
+        } = expr
+        {
+            if span.context().as_ref() == file
+                && contains(&(span.start()..span.end()), ident_offset)
+            {
+                for (index, _) in gen_ranges {
+                    items.push(ImCompleteCompletionItem::Variable {
+                        name: local_name(&index.to_string()).to_string(),
+                        ty: Some("int".to_string()),
+                    });
+                }
+            }
+        }
     });
-    map
+    items
+}
+
+/// The type written in the declaration whose name is at `span` in `ast`.
+fn declared_type(ast: &Ast, span: &Span) -> Option<String> {
+    let mut ty = None;
+    for decl in ast {
+        decl.walk(&mut |decl| {
+            let found = match decl {
+                Decl::Const { name, ty, .. }
+                | Decl::NewType { name, ty, .. }
+                | Decl::Let { name, ty, .. }
+                    if name.span == *span =>
+                {
+                    ty.as_ref()
+                }
+                Decl::Union { variants, .. } => variants
+                    .iter()
+                    .find(|variant| variant.name.span == *span)
+                    .and_then(|variant| variant.ty.as_ref()),
+                Decl::Predicate { params, .. } => params
+                    .iter()
+                    .find(|param| param.name.span == *span)
+                    .and_then(|param| param.ty.as_ref()),
+                Decl::Storage { vars, .. } => vars
+                    .iter()
+                    .find(|var| var.name.span == *span)
+                    .and_then(|var| var.ty.as_ref()),
+                _ => None,
+            };
+            if let Some(found) = found {
+                ty = Some(found.to_string());
+            }
+        });
+    }
+    ty
+}
+
+/// The comments on the lines right above the one `offset` is on in `text`, with their `//`
+/// or `///` taken off. `comments` are the comments the lexer found in `text`.
+fn doc_comment(comments: &[Spanned<String>], text: &str, offset: usize) -> Option<String> {
+    let line_start = |offset: usize| {
+        text.get(..offset)
+            .map(|before| before.rfind('\n').map_or(0, |newline| newline + 1))
+    };
+    let mut end = line_start(offset)?;
+    let first_line = end;
+    let above = comments
+        .iter()
+        .rev()
+        .skip_while(|(_, span)| span.end > first_line);
+    let mut lines = vec![];
+    for (comment, span) in above {
+        let start = line_start(span.start)?;
+        let own_line = text.get(start..span.start)?.trim().is_empty();
+        if !own_line || !text.get(span.end..end)?.trim().is_empty() {
+            break;
+        }
+        let comment = comment.trim_start_matches('/');
+        lines.push(comment.strip_prefix(' ').unwrap_or(comment).trim_end());
+        end = start;
+    }
+    lines.reverse();
+    (!lines.is_empty()).then(|| lines.join("\n"))
 }
 
+/// The details of the declaration whose name is at `span` in `text`, of which `ast` is the
+/// syntax tree and `comments` the comments. There are none if `span` is not in `text`.
+pub fn declaration_details(
+    ast: &Ast,
+    comments: &[Spanned<String>],
+    text: &str,
+    span: &Span,
+) -> Option<Details> {
+    let line_start = text
+        .get(..span.start)?
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    let line_end = text
+        .get(span.start..)?
+        .find('\n')
+        .map_or(text.len(), |newline| span.start + newline);
+    let storage_key = ast.iter().find_map(|decl| match decl {
+        Decl::Storage { vars, .. } => vars.iter().position(|var| var.name.span == *span),
+        _ => None,
+    });
+    Some(Details {
+        ty: declared_type(ast, span),
+        source: text.get(line_start..line_end)?.trim().to_string(),
+        doc: doc_comment(comments, text, span.start),
+        storage_key,
+    })
+}
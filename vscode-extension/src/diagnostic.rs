@@ -0,0 +1,165 @@
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Arc;
+
+use pintc::error::{Error, ReportableError};
+use pintc::warning::{ReportableWarning, Warning};
+use tower_lsp::lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString, Range};
+
+/// A byte range in a file pintc read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceSpan {
+    pub file: Arc<Path>,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A pintc error or warning taken apart into what an LSP diagnostic needs.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub severity: DiagnosticSeverity,
+    pub code: String,
+    pub message: String,
+    pub span: SourceSpan,
+    /// Every labelled span of the report, the primary one included.
+    pub labels: Vec<(SourceSpan, String)>,
+    pub note: Option<String>,
+    pub help: Option<String>,
+}
+
+impl Report {
+    pub fn from_error(error: &Error) -> Self {
+        let report: &dyn ReportableError = error;
+        let span = report.span();
+        let span = SourceSpan {
+            file: span.context(),
+            start: span.start(),
+            end: span.end(),
+        };
+        Report {
+            severity: DiagnosticSeverity::ERROR,
+            code: error_code(error),
+            message: error.to_string(),
+            labels: labels(&report.display_raw(), &format!("{error:?}"), &span),
+            span,
+            note: report.note(),
+            help: report.help(),
+        }
+    }
+
+    pub fn from_warning(warning: &Warning) -> Self {
+        let report: &dyn ReportableWarning = warning;
+        let span = report.span();
+        let span = SourceSpan {
+            file: span.context(),
+            start: span.start(),
+            end: span.end(),
+        };
+        Report {
+            severity: DiagnosticSeverity::WARNING,
+            code: warning_code(warning),
+            message: warning.to_string(),
+            labels: labels(&report.display_raw(), &format!("{warning:?}"), &span),
+            span,
+            note: report.note(),
+            help: report.help(),
+        }
+    }
+}
+
+pub fn pintc_diagnostic(
+    range: Range,
+    severity: DiagnosticSeverity,
+    code: Option<String>,
+    message: String,
+) -> Diagnostic {
+    Diagnostic {
+        range,
+        severity: Some(severity),
+        code: code.map(NumberOrString::String),
+        source: Some("pintc".to_string()),
+        message,
+        ..Default::default()
+    }
+}
+
+/// A stable code for `error`. pintc's own codes are used where it has them, otherwise the code is
+/// the error kind, e.g. `parse::ExpectedFound` or `compile::SymbolNotFound`.
+fn error_code(error: &Error) -> String {
+    if let Some(code) = ReportableError::code(error) {
+        return code;
+    }
+    match error {
+        Error::Lex { error, .. } => format!("lex::{}", variant_name(error)),
+        Error::Parse { error } => format!("parse::{}", variant_name(error)),
+        Error::Compile { error } => format!("compile::{}", variant_name(error)),
+        Error::MacroBodyWrapper { child, .. } => error_code(child),
+    }
+}
+
+fn warning_code(warning: &Warning) -> String {
+    ReportableWarning::code(warning)
+        .unwrap_or_else(|| format!("warning::{}", variant_name(warning)))
+}
+
+/// The name of the enum variant `value` is, taken from its derived `Debug` output since pintc
+/// does not export its error kinds.
+fn variant_name(value: &impl Debug) -> String {
+    format!("{value:?}")
+        .chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_')
+        .collect()
+}
+
+/// The labels of a report. pintc keeps label fields private, but `display_raw` lists each label
+/// as `@start..end: message`, and the spans in the `Debug` output (`"file":start..end`) tell
+/// which file each range is in. A range that matches no span is taken to be in the primary file.
+fn labels(display_raw: &str, debug: &str, primary: &SourceSpan) -> Vec<(SourceSpan, String)> {
+    let spans = debug_spans(debug);
+    display_raw
+        .lines()
+        .filter_map(|line| {
+            let (range, message) = line.strip_prefix('@')?.split_once(": ")?;
+            let (start, end) = range.split_once("..")?;
+            let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+            let file = spans
+                .iter()
+                .find(|span| span.start == start && span.end == end)
+                .map_or_else(|| primary.file.clone(), |span| span.file.clone());
+            Some((SourceSpan { file, start, end }, message.to_string()))
+        })
+        .collect()
+}
+
+fn debug_spans(debug: &str) -> Vec<SourceSpan> {
+    let mut spans = vec![];
+    let mut rest = debug;
+    while let Some(at) = rest.find("\":") {
+        let (before, after) = (&rest[..at], &rest[at + 2..]);
+        rest = after;
+        let Some(open) = before.rfind('"') else {
+            continue;
+        };
+        let Some((start, after)) = split_number(after) else {
+            continue;
+        };
+        let Some(after) = after.strip_prefix("..") else {
+            continue;
+        };
+        let Some((end, _)) = split_number(after) else {
+            continue;
+        };
+        let file = before[open + 1..].replace("\\\\", "\\");
+        spans.push(SourceSpan {
+            file: Arc::from(Path::new(&file)),
+            start,
+            end,
+        });
+    }
+    spans
+}
+
+fn split_number(text: &str) -> Option<(usize, &str)> {
+    let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    Some((text[..digits].parse().ok()?, &text[digits..]))
+}
@@ -0,0 +1,161 @@
+use tower_lsp::lsp_types::SymbolKind;
+
+use crate::chumsky::{Ast, Decl, Ident, Param, Span, StorageVar};
+
+/// A document symbol with byte spans, before they are turned into LSP ranges.
+#[derive(Debug, Clone)]
+pub struct ImCompleteDocumentSymbol {
+    pub name: String,
+    pub detail: Option<String>,
+    pub kind: SymbolKind,
+    /// The whole declaration.
+    pub span: Span,
+    /// The name of the declaration.
+    pub selection_span: Span,
+    pub children: Vec<ImCompleteDocumentSymbol>,
+}
+
+impl ImCompleteDocumentSymbol {
+    fn new(name: &Ident, kind: SymbolKind, span: Span) -> Self {
+        ImCompleteDocumentSymbol {
+            name: name.name.clone(),
+            detail: None,
+            kind,
+            span,
+            selection_span: name.span.clone(),
+            children: vec![],
+        }
+    }
+
+    fn detail(mut self, detail: Option<String>) -> Self {
+        self.detail = detail;
+        self
+    }
+
+    fn children(mut self, children: Vec<ImCompleteDocumentSymbol>) -> Self {
+        self.children = children;
+        self
+    }
+}
+
+fn param_symbols(params: &[Param]) -> Vec<ImCompleteDocumentSymbol> {
+    params
+        .iter()
+        .map(|param| {
+            ImCompleteDocumentSymbol::new(&param.name, SymbolKind::VARIABLE, param.span.clone())
+                .detail(param.ty.as_ref().map(ToString::to_string))
+        })
+        .collect()
+}
+
+fn field_symbols(vars: &[StorageVar]) -> Vec<ImCompleteDocumentSymbol> {
+    vars.iter()
+        .map(|var| {
+            ImCompleteDocumentSymbol::new(&var.name, SymbolKind::FIELD, var.span.clone())
+                .detail(var.ty.as_ref().map(ToString::to_string))
+        })
+        .collect()
+}
+
+/// The outline of `ast`: its top level declarations, with the members, parameters and `let`s
+/// declared inside them as children.
+pub fn document_symbols(ast: &Ast) -> Vec<ImCompleteDocumentSymbol> {
+    let mut symbols = vec![];
+    for decl in ast {
+        let symbol = match decl {
+            Decl::Storage { vars, span } => {
+                // The block is named by its keyword, which it starts with.
+                let name = Ident {
+                    name: "storage".to_string(),
+                    span: span.start..span.start + "storage".len(),
+                };
+                ImCompleteDocumentSymbol::new(&name, SymbolKind::STRUCT, span.clone())
+                    .children(field_symbols(vars))
+            }
+            Decl::Predicate {
+                name, params, span, ..
+            } => {
+                let mut children = param_symbols(params);
+                decl.walk(&mut |decl| {
+                    if let Decl::Let { name, ty, span, .. } = decl {
+                        children.push(
+                            ImCompleteDocumentSymbol::new(name, SymbolKind::VARIABLE, span.clone())
+                                .detail(ty.as_ref().map(ToString::to_string)),
+                        );
+                    }
+                });
+                ImCompleteDocumentSymbol::new(name, SymbolKind::FUNCTION, span.clone())
+                    .children(children)
+            }
+            Decl::NewType { name, ty, span } => {
+                ImCompleteDocumentSymbol::new(name, SymbolKind::STRUCT, span.clone())
+                    .detail(ty.as_ref().map(ToString::to_string))
+            }
+            Decl::Union {
+                name,
+                variants,
+                span,
+            } => {
+                // Variants have no span of their own beyond their name.
+                let variants = variants
+                    .iter()
+                    .map(|variant| {
+                        let span = variant.name.span.clone();
+                        ImCompleteDocumentSymbol::new(&variant.name, SymbolKind::ENUM_MEMBER, span)
+                            .detail(variant.ty.as_ref().map(ToString::to_string))
+                    })
+                    .collect();
+                ImCompleteDocumentSymbol::new(name, SymbolKind::ENUM, span.clone())
+                    .children(variants)
+            }
+            Decl::Const { name, ty, span, .. } => {
+                ImCompleteDocumentSymbol::new(name, SymbolKind::CONSTANT, span.clone())
+                    .detail(ty.as_ref().map(ToString::to_string))
+            }
+            Decl::Macro {
+                name,
+                params,
+                pack,
+                span,
+                ..
+            } => {
+                let params = params
+                    .iter()
+                    .chain(pack)
+                    .map(|param| {
+                        ImCompleteDocumentSymbol::new(
+                            param,
+                            SymbolKind::VARIABLE,
+                            param.span.clone(),
+                        )
+                    })
+                    .collect();
+                ImCompleteDocumentSymbol::new(name, SymbolKind::FUNCTION, span.clone())
+                    .children(params)
+            }
+            Decl::Interface {
+                name,
+                storage,
+                predicates,
+                span,
+            } => {
+                // The storage block of an interface has no span of its own, so its fields go
+                // straight under the interface.
+                let mut children = field_symbols(storage);
+                children.extend(predicates.iter().map(|predicate| {
+                    ImCompleteDocumentSymbol::new(
+                        &predicate.name,
+                        SymbolKind::FUNCTION,
+                        predicate.span.clone(),
+                    )
+                    .children(param_symbols(&predicate.params))
+                }));
+                ImCompleteDocumentSymbol::new(name, SymbolKind::INTERFACE, span.clone())
+                    .children(children)
+            }
+            _ => continue,
+        };
+        symbols.push(symbol);
+    }
+    symbols
+}
@@ -0,0 +1,213 @@
+use std::path::Path;
+
+use pintc::expr::Expr as PintExpr;
+use pintc::predicate::Contract;
+
+use crate::chumsky::{Ast, Decl, Ident, Param, Span};
+use crate::jump_definition::{contains, visit_contract};
+use crate::snapshot::Snapshot;
+
+/// What pintc worked out about an expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprInfo {
+    pub span: Span,
+    /// The type of the expression, unless pintc could not infer one.
+    pub ty: Option<String>,
+    /// For an access to a field of the `storage` block, whether it is `mut`.
+    pub mutable: Option<bool>,
+}
+
+/// The innermost expression at `offset` in `file`, a staged path. Offsets are in the text the
+/// contract was built from.
+pub fn expr_at(contract: &Contract, file: &Path, offset: usize) -> Option<ExprInfo> {
+    let mut best: Option<(usize, ExprInfo)> = None;
+    visit_contract(contract, |key, expr| {
+        let pint_span = contract.expr_key_to_span(key);
+        let span = pint_span.start()..pint_span.end();
+        if pint_span.context().as_ref() != file || !contains(&span, offset) {
+            return;
+        }
+        let len = span.end - span.start;
+        if best.as_ref().is_some_and(|(best_len, _)| *best_len <= len) {
+            return;
+        }
+        let ty = key.get_ty(contract);
+        let mutable = match expr {
+            PintExpr::LocalStorageAccess { mutable, .. } => Some(*mutable),
+            _ => None,
+        };
+        let info = ExprInfo {
+            span,
+            ty: (!ty.is_unknown() && !ty.is_error()).then(|| contract.with_ctrct(ty).to_string()),
+            mutable,
+        };
+        best = Some((len, info));
+    });
+    best.map(|(_, info)| info)
+}
+
+/// What pintc worked out about a parameter or `let`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalInfo {
+    /// The type, unless pintc could not infer one.
+    pub ty: Option<String>,
+    /// The name, if it has one, and type of each field when the type is a tuple.
+    pub fields: Vec<(Option<String>, String)>,
+}
+
+/// What pintc worked out about the parameter or `let` named by `name` in `file`, a staged path.
+/// `name` is in the current text of the document `snapshot` is of.
+pub fn local_info(snapshot: &Snapshot, file: &Path, name: &Ident) -> Option<LocalInfo> {
+    let contract = &snapshot.contract;
+    let offset = snapshot.edits.to_snapshot(name.span.start);
+    // pintc keeps the full path of a local as its name, and the whole declaration as its span.
+    let own = |file_name: &Path, span: Span, full_name: &str| {
+        file_name == file
+            && contains(&span, offset)
+            && full_name.rsplit("::").next() == Some(name.name.as_str())
+    };
+    let mut param = None;
+    let mut variable = None;
+    for pred in contract.preds.values() {
+        param = pred.params.iter().find(|param| {
+            let span = &param.span;
+            own(
+                span.context().as_ref(),
+                span.start()..span.end(),
+                &param.name.to_string(),
+            )
+        });
+        variable = pred.variables.variables().find_map(|(key, var)| {
+            let span = &var.span;
+            own(span.context().as_ref(), span.start()..span.end(), &var.name).then_some((pred, key))
+        });
+        if param.is_some() || variable.is_some() {
+            break;
+        }
+    }
+    let ty = match (param, &variable) {
+        (Some(param), _) => &param.ty,
+        (None, Some((pred, key))) => key.get_ty(pred),
+        (None, None) => return None,
+    };
+    let fields = ty
+        .get_tuple_fields()
+        .into_iter()
+        .flatten()
+        .map(|(name, ty)| {
+            (
+                name.as_ref().map(ToString::to_string),
+                contract.with_ctrct(ty).to_string(),
+            )
+        })
+        .collect();
+    Some(LocalInfo {
+        ty: (!ty.is_unknown() && !ty.is_error()).then(|| contract.with_ctrct(ty).to_string()),
+        fields,
+    })
+}
+
+/// The type pintc inferred for the `let` named by `name` in `file`, a staged path. `name` is in
+/// the current text of the document `snapshot` is of.
+pub fn let_type(snapshot: &Snapshot, file: &Path, name: &Ident) -> Option<String> {
+    local_info(snapshot, file, name)?.ty
+}
+
+/// `let name: type` for a `let` without a type annotation whose name is at `span` in `ast`,
+/// with the type pintc inferred for it.
+pub fn let_signature(ast: &Ast, span: &Span, snapshot: &Snapshot, file: &Path) -> Option<String> {
+    let mut name = None;
+    for decl in ast {
+        decl.walk(&mut |decl| {
+            if let Decl::Let {
+                name: let_name,
+                ty: None,
+                ..
+            } = decl
+            {
+                if let_name.span == *span {
+                    name = Some(let_name);
+                }
+            }
+        });
+    }
+    let name = name?;
+    Some(format!(
+        "let {}: {}",
+        name.name,
+        let_type(snapshot, file, name)?
+    ))
+}
+
+fn params(params: &[Param]) -> String {
+    params
+        .iter()
+        .map(|param| match &param.ty {
+            Some(ty) => format!("{}: {ty}", param.name.name),
+            None => param.name.name.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The signature of the predicate, type, union, macro, const or interface whose name is at
+/// `span` in `ast`, as it would be declared without a body.
+pub fn signature(ast: &Ast, span: &Span) -> Option<String> {
+    ast.iter().find_map(|decl| match decl {
+        Decl::Predicate {
+            name, params: ps, ..
+        } if name.span == *span => Some(format!("predicate {}({})", name.name, params(ps))),
+        Decl::NewType { name, ty, .. } if name.span == *span => Some(match ty {
+            Some(ty) => format!("type {} = {ty}", name.name),
+            None => format!("type {}", name.name),
+        }),
+        Decl::Union { name, variants, .. } if name.span == *span => {
+            let variants = variants
+                .iter()
+                .map(|variant| match &variant.ty {
+                    Some(ty) => format!("{}({ty})", variant.name.name),
+                    None => variant.name.name.clone(),
+                })
+                .collect::<Vec<_>>()
+                .join(" | ");
+            Some(format!("union {} = {variants}", name.name))
+        }
+        Decl::Macro {
+            name, params, pack, ..
+        } if name.span == *span => {
+            let params = params
+                .iter()
+                .chain(pack)
+                .map(|param| param.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            Some(format!("macro {}({params})", name.name))
+        }
+        Decl::Const { name, ty, .. } if name.span == *span => Some(match ty {
+            Some(ty) => format!("const {}: {ty}", name.name),
+            None => format!("const {}", name.name),
+        }),
+        Decl::Interface { name, .. } if name.span == *span => {
+            Some(format!("interface {}", name.name))
+        }
+        _ => None,
+    })
+}
+
+/// Hover text in Markdown: `code` as Pint, then `doc`, then whether a storage access is `mut`.
+/// `stale` notes that what pintc worked out is from before the latest edits.
+pub fn hover_markdown(code: &str, doc: Option<&str>, mutable: Option<bool>, stale: bool) -> String {
+    let mut markdown = format!("```pint\n{code}\n```");
+    if let Some(doc) = doc {
+        markdown.push_str(&format!("\n\n---\n\n{doc}"));
+    }
+    match mutable {
+        Some(true) => markdown.push_str("\n\n`mut` storage access"),
+        Some(false) => markdown.push_str("\n\nRead-only storage access"),
+        None => {}
+    }
+    if stale {
+        markdown.push_str("\n\n*As of the last build; the document has changed since.*");
+    }
+    markdown
+}
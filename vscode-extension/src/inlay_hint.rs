@@ -0,0 +1,98 @@
+use std::path::Path;
+
+use tower_lsp::lsp_types::InlayHintKind;
+
+use crate::chumsky::{Ast, Decl, Expr};
+use crate::hover::let_type;
+use crate::intrinsics::Intrinsic;
+use crate::snapshot::Snapshot;
+
+/// An inlay hint at a byte offset, before it is turned into an LSP position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImCompleteInlayHint {
+    pub offset: usize,
+    pub label: String,
+    pub kind: InlayHintKind,
+}
+
+/// `: type` after the name of every `let` in `ast` without a type annotation, with the type
+/// pintc inferred for it. `file` is the staged path of the document `snapshot` is of.
+pub fn let_type_hints(ast: &Ast, snapshot: &Snapshot, file: &Path) -> Vec<ImCompleteInlayHint> {
+    let mut hints = vec![];
+    for decl in ast {
+        decl.walk(&mut |decl| {
+            let Decl::Let { name, ty: None, .. } = decl else {
+                return;
+            };
+            if let Some(ty) = let_type(snapshot, file, name) {
+                hints.push(ImCompleteInlayHint {
+                    offset: name.span.end,
+                    label: format!(": {ty}"),
+                    kind: InlayHintKind::TYPE,
+                });
+            }
+        });
+    }
+    hints
+}
+
+/// `name:` before the arguments of calls to intrinsics and to the macros declared in `ast`.
+/// Arguments that are already a path of that name go without.
+pub fn parameter_hints(ast: &Ast) -> Vec<ImCompleteInlayHint> {
+    let mut hints = vec![];
+    let mut push = |params: &mut dyn Iterator<Item = &str>, args: &[Expr]| {
+        for (param, arg) in params.zip(args) {
+            let named = matches!(arg, Expr::Path(path)
+                if path.segments.len() == 1 && path.segments[0].name == param);
+            if !named {
+                hints.push(ImCompleteInlayHint {
+                    offset: arg.span().start,
+                    label: format!("{param}:"),
+                    kind: InlayHintKind::PARAMETER,
+                });
+            }
+        }
+    };
+    for decl in ast {
+        decl.walk(&mut |decl| {
+            for expr in decl.exprs() {
+                expr.walk(&mut |expr| match expr {
+                    Expr::IntrinsicCall { name, args, .. } => {
+                        if let Some(intrinsic) = Intrinsic::find(&name.name) {
+                            push(&mut intrinsic.params.iter().map(|(name, _)| *name), args);
+                        }
+                    }
+                    Expr::MacroCall(call) => {
+                        let Some(name) = call.name.segments.last() else {
+                            return;
+                        };
+                        // Macros are overloaded by their number of parameters. Arguments taken
+                        // by a parameter pack get no hint.
+                        let params = ast.iter().find_map(|decl| match decl {
+                            Decl::Macro {
+                                name: decl_name,
+                                params,
+                                pack,
+                                ..
+                            } if decl_name.name == name.name
+                                && (params.len() == call.args.len()
+                                    || pack.is_some() && params.len() <= call.args.len()) =>
+                            {
+                                Some(params)
+                            }
+                            _ => None,
+                        });
+                        if let Some(params) = params {
+                            push(
+                                &mut params.iter().map(|param| param.name.as_str()),
+                                &call.args,
+                            );
+                        }
+                    }
+                    _ => {}
+                });
+            }
+        });
+    }
+    hints
+}
@@ -0,0 +1,146 @@
+/// An intrinsic pintc provides, as it can be called from Pint.
+pub struct Intrinsic {
+    pub name: &'static str,
+    /// Parameter names and types. `_` stands for a value of any type.
+    pub params: &'static [(&'static str, &'static str)],
+    pub ret: &'static str,
+    pub doc: &'static str,
+}
+
+/// The intrinsics of pintc 0.10. Its internal ones, which only appear in lowered code, are left
+/// out.
+pub const INTRINSICS: &[Intrinsic] = &[
+    Intrinsic {
+        name: "__address_of",
+        params: &[("predicate", "string")],
+        ret: "b256",
+        doc: "Returns the address of a predicate in the same contract, named by its path.",
+    },
+    Intrinsic {
+        name: "__recover_secp256k1",
+        params: &[("data_hash", "b256"), ("signature", "{ b256, b256, int }")],
+        ret: "{ b256, int }",
+        doc: "Recovers the public key from a secp256k1 signature.",
+    },
+    Intrinsic {
+        name: "__sha256",
+        params: &[("data", "_")],
+        ret: "b256",
+        doc: "Returns the SHA-256 hash of `data`.",
+    },
+    Intrinsic {
+        name: "__size_of",
+        params: &[("value", "_")],
+        ret: "int",
+        doc: "Returns the size of an expression, in words.",
+    },
+    Intrinsic {
+        name: "__this_address",
+        params: &[],
+        ret: "b256",
+        doc: "Returns the content hash of this predicate.",
+    },
+    Intrinsic {
+        name: "__this_contract_address",
+        params: &[],
+        ret: "b256",
+        doc: "Returns the content hash of the contract this predicate belongs to.",
+    },
+    Intrinsic {
+        name: "__vec_len",
+        params: &[("vector", "_[]")],
+        ret: "int",
+        doc: "Returns the length of a storage vector.",
+    },
+    Intrinsic {
+        name: "__verify_ed25519",
+        params: &[
+            ("data", "_"),
+            ("signature", "{ b256, b256 }"),
+            ("public_key", "b256"),
+        ],
+        ret: "bool",
+        doc: "Checks an Ed25519 signature of `data` against a public key.",
+    },
+];
+
+impl Intrinsic {
+    pub fn find(name: &str) -> Option<&'static Intrinsic> {
+        INTRINSICS.iter().find(|intrinsic| intrinsic.name == name)
+    }
+
+    fn param_label(param: &(&str, &str)) -> String {
+        format!("{}: {}", param.0, param.1)
+    }
+
+    /// `__sha256(data: _) -> b256`.
+    pub fn signature(&self) -> String {
+        let params = self
+            .params
+            .iter()
+            .map(Self::param_label)
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("{}({params}) -> {}", self.name, self.ret)
+    }
+
+    /// Where each parameter is in [`Intrinsic::signature`], as byte offsets.
+    pub fn param_offsets(&self) -> Vec<(usize, usize)> {
+        let mut start = self.name.len() + 1;
+        self.params
+            .iter()
+            .map(|param| {
+                let end = start + Self::param_label(param).len();
+                let offsets = (start, end);
+                start = end + ", ".len();
+                offsets
+            })
+            .collect()
+    }
+
+    /// The call as a completion snippet, with a tab stop for each argument.
+    pub fn snippet(&self) -> String {
+        let args = self
+            .params
+            .iter()
+            .enumerate()
+            .map(|(index, (name, _))| format!("${{{}:{name}}}", index + 1))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("{}({args})", self.name)
+    }
+}
+
+/// The call whose argument list `offset` is in, by the name before its `(`, and the index of
+/// the argument the offset is in. Works on the text alone, so calls being typed are found too.
+pub fn call_at(text: &str, offset: usize) -> Option<(&str, usize)> {
+    let before = text.get(..offset)?;
+    let mut depth = 0;
+    let mut commas = 0;
+    let mut in_string = false;
+    for (index, c) in before.char_indices().rev() {
+        if in_string {
+            in_string = c != '"' || before[..index].ends_with('\\');
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            ')' | ']' | '}' => depth += 1,
+            '[' | '{' if depth == 0 => return None,
+            '(' | '[' | '{' => depth -= 1,
+            ',' if depth == 0 => commas += 1,
+            // Statements do not span argument lists.
+            ';' if depth == 0 => return None,
+            _ => {}
+        }
+        if c == '(' && depth < 0 {
+            let head = before[..index].trim_end();
+            let name_start = head
+                .trim_end_matches(|c: char| c.is_ascii_alphanumeric() || c == '_')
+                .len();
+            let name = &head[name_start..];
+            return (!name.is_empty()).then_some((name, commas));
+        }
+    }
+    None
+}
@@ -1,129 +1,477 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
 
-use im_rc::Vector;
+use pintc::expr::Expr as PintExpr;
+use pintc::predicate::{Contract, ExprKey};
 
-use crate::chumsky::{Expr, Func, Spanned};
-/// return (need_to_continue_search, founded reference)
-pub fn get_definition(ast: &HashMap<String, Func>, ident_offset: usize) -> Option<Spanned<String>> {
-    let mut vector = Vector::new();
-    for (_, v) in ast.iter() {
-        if v.name.1.start < ident_offset && v.name.1.end > ident_offset {
-            return Some(v.name.clone());
+use crate::chumsky::{Ast, Decl, Expr, Ident, Path, Span, Spanned, Type, UseTree};
+
+pub(crate) fn contains(span: &Span, offset: usize) -> bool {
+    span.start <= offset && offset <= span.end
+}
+
+fn spanned(ident: &Ident) -> Spanned<String> {
+    (ident.name.clone(), ident.span.clone())
+}
+
+/// The name a top level declaration introduces.
+fn global_name(decl: &Decl) -> Option<&Ident> {
+    match decl {
+        Decl::Const { name, .. }
+        | Decl::NewType { name, .. }
+        | Decl::Union { name, .. }
+        | Decl::Interface { name, .. }
+        | Decl::Predicate { name, .. }
+        | Decl::Macro { name, .. } => Some(name),
+        _ => None,
+    }
+}
+
+/// Every name declared in `ast`, from top level declarations down to generator indices.
+fn declared_names(ast: &Ast) -> Vec<&Ident> {
+    let mut names = vec![];
+    for decl in ast {
+        names.extend(global_name(decl));
+        match decl {
+            Decl::Union { variants, .. } => {
+                names.extend(variants.iter().map(|variant| &variant.name))
+            }
+            Decl::Storage { vars, .. } => names.extend(vars.iter().map(|var| &var.name)),
+            Decl::Interface {
+                storage,
+                predicates,
+                ..
+            } => {
+                names.extend(storage.iter().map(|var| &var.name));
+                for predicate in predicates {
+                    names.push(&predicate.name);
+                    names.extend(predicate.params.iter().map(|param| &param.name));
+                }
+            }
+            _ => {}
         }
-        if v.name.1.end < ident_offset {
-            vector.push_back(v.name.clone());
+        names.extend(locals(decl));
+        decl.walk(&mut |decl| {
+            if let Decl::Match { arms, .. } = decl {
+                names.extend(arms.iter().filter_map(|arm| arm.binding.as_ref()));
+            }
+            for expr in decl.exprs() {
+                expr.walk(&mut |expr| match expr {
+                    Expr::Generator { ranges, .. } => {
+                        names.extend(ranges.iter().map(|(index, _)| index))
+                    }
+                    Expr::Match { arms, .. } => {
+                        names.extend(arms.iter().filter_map(|arm| arm.binding.as_ref()))
+                    }
+                    _ => {}
+                });
+            }
+        });
+    }
+    names
+}
+
+/// The parameters and `let`s of a predicate or macro.
+pub(crate) fn locals(decl: &Decl) -> Vec<&Ident> {
+    let mut locals = vec![];
+    match decl {
+        Decl::Predicate { params, .. } => locals.extend(params.iter().map(|param| &param.name)),
+        Decl::Macro { params, pack, .. } => locals.extend(params.iter().chain(pack)),
+        _ => {}
+    }
+    decl.walk(&mut |decl| {
+        if let Decl::Let { name, .. } = decl {
+            locals.push(name);
+        }
+    });
+    locals
+}
+
+fn type_paths<'a>(ty: &'a Type, paths: &mut Vec<&'a Path>) {
+    match ty {
+        Type::Error(_) | Type::Primitive(..) => {}
+        Type::Custom(path) => paths.push(path),
+        Type::Tuple(fields, _) => {
+            for (_, ty) in fields {
+                type_paths(ty, paths);
+            }
         }
+        Type::Array { ty, size, .. } => {
+            type_paths(ty, paths);
+            expr_paths(size, paths);
+        }
+        Type::Vector { ty, .. } => type_paths(ty, paths),
+        Type::Map { from, to, .. } => {
+            type_paths(from, paths);
+            type_paths(to, paths);
+        }
+    }
+}
+
+fn expr_paths<'a>(expr: &'a Expr, paths: &mut Vec<&'a Path>) {
+    expr.walk(&mut |expr| match expr {
+        Expr::Path(path)
+        | Expr::UnionVariant { path, .. }
+        | Expr::PredicateCall { path, .. }
+        | Expr::ExternalStorage {
+            interface: path, ..
+        } => paths.push(path),
+        Expr::MacroCall(call) => paths.push(&call.name),
+        Expr::Cast { ty, .. } => type_paths(ty, paths),
+        Expr::Match { arms, .. } => paths.extend(arms.iter().map(|arm| &arm.pattern)),
+        _ => {}
+    });
+}
+
+/// Every path in `ast` that refers to a declaration: values, types, union variants, predicates,
+/// interfaces and macros.
+pub fn name_paths(ast: &Ast) -> Vec<&Path> {
+    let mut paths = vec![];
+    for decl in ast {
+        decl.walk(&mut |decl| {
+            for ty in decl.types() {
+                type_paths(ty, &mut paths);
+            }
+            for expr in decl.exprs() {
+                expr_paths(expr, &mut paths);
+            }
+            if let Decl::Match { arms, .. } = decl {
+                paths.extend(arms.iter().map(|arm| &arm.pattern));
+            }
+        });
     }
+    paths
+}
+
+/// The path with a segment at `offset`, and the index of that segment. In `Dir::Up` the cursor on
+/// `Dir` names the union and on `Up` the variant, so callers cut the path after that segment.
+pub fn path_at(ast: &Ast, offset: usize) -> Option<(&Path, usize)> {
+    name_paths(ast).into_iter().find_map(|path| {
+        let at = path
+            .segments
+            .iter()
+            .position(|segment| contains(&segment.span, offset))?;
+        Some((path, at))
+    })
+}
 
-    for (_, v) in ast.iter() {
-        let args = v.args.iter().cloned().collect::<Vector<_>>();
-        if let (_, Some(value)) =
-            get_definition_of_expr(&v.body, args + vector.clone(), ident_offset)
-        {
-            return Some(value);
+/// The top level declaration of `ast` called `name`. Union variants are found as `Union::Variant`.
+pub fn find_declaration(ast: &Ast, name: &str) -> Option<Spanned<String>> {
+    ast.iter().find_map(|decl| {
+        if let Some(global) = global_name(decl).filter(|global| global.name == name) {
+            return Some(spanned(global));
         }
+        let Decl::Union {
+            name: union,
+            variants,
+            ..
+        } = decl
+        else {
+            return None;
+        };
+        let variant = name.strip_prefix(&union.name)?.strip_prefix("::")?;
+        variants
+            .iter()
+            .find(|candidate| candidate.name.name == variant)
+            .map(|variant| spanned(&variant.name))
+    })
+}
+
+/// Storage fields and interface members named at `ident_offset`, which are found through the
+/// block or interface they belong to rather than by path.
+fn member_definition(ast: &Ast, ident_offset: usize) -> Option<Spanned<String>> {
+    let interface = |path: &Path| {
+        ast.iter().find_map(|decl| match decl {
+            Decl::Interface {
+                name,
+                storage,
+                predicates,
+                ..
+            } if path.name().is_some_and(|last| last.name == name.name) => {
+                Some((storage, predicates))
+            }
+            _ => None,
+        })
+    };
+
+    let mut found = None;
+    for decl in ast {
+        decl.walk(&mut |decl| {
+            for expr in decl.exprs() {
+                expr.walk(&mut |expr| match expr {
+                    Expr::Storage {
+                        name: Some(name), ..
+                    } if contains(&name.span, ident_offset) => {
+                        found = ast
+                            .iter()
+                            .filter_map(|decl| match decl {
+                                Decl::Storage { vars, .. } => Some(vars),
+                                _ => None,
+                            })
+                            .flatten()
+                            .find(|var| var.name.name == name.name)
+                            .map(|var| spanned(&var.name));
+                    }
+                    Expr::ExternalStorage {
+                        interface: path,
+                        name: Some(name),
+                        ..
+                    } if contains(&name.span, ident_offset) => {
+                        found = interface(path).and_then(|(storage, _)| {
+                            storage
+                                .iter()
+                                .find(|var| var.name.name == name.name)
+                                .map(|var| spanned(&var.name))
+                        });
+                    }
+                    Expr::PredicateCall {
+                        path,
+                        predicate: Some(predicate),
+                        ..
+                    } if contains(&predicate.span, ident_offset) => {
+                        found = interface(path).and_then(|(_, predicates)| {
+                            predicates
+                                .iter()
+                                .find(|candidate| candidate.name.name == predicate.name)
+                                .map(|candidate| spanned(&candidate.name))
+                        });
+                    }
+                    _ => {}
+                });
+            }
+        });
     }
-    None
-}
-
-pub fn get_definition_of_expr(
-    expr: &Spanned<Expr>,
-    definition_ass_list: Vector<Spanned<String>>,
-    ident_offset: usize,
-) -> (bool, Option<Spanned<String>>) {
-    match &expr.0 {
-        Expr::Error => (true, None),
-        Expr::Value(_) => (true, None),
-        // Expr::List(exprs) => exprs
-        //     .iter()
-        //     .for_each(|expr| get_definition(expr, definition_ass_list)),
-        Expr::Local(local) => {
-            if ident_offset >= local.1.start && ident_offset < local.1.end {
-                let index = definition_ass_list
+    found
+}
+
+/// The declaration in scope at `ident_offset` inside `decl` that is called `name`, looking at
+/// generator indices and match bindings around the offset first, then parameters and `let`s.
+fn local_definition(decl: &Decl, name: &str, ident_offset: usize) -> Option<Spanned<String>> {
+    // Generator indices and match bindings are only in scope inside their expression or arm.
+    let mut bound = vec![];
+    decl.walk(&mut |decl| {
+        if let Decl::Match { arms, .. } = decl {
+            for arm in arms {
+                if arm
+                    .body
                     .iter()
-                    .position(|decl| decl.0 == local.0);
-                (
-                    false,
-                    index.map(|i| definition_ass_list.get(i).unwrap().clone()),
-                )
-            } else {
-                (true, None)
+                    .any(|decl| contains(&decl.span(), ident_offset))
+                {
+                    bound.extend(arm.binding.as_ref());
+                }
+            }
+        }
+        for expr in decl.exprs() {
+            expr.walk(&mut |expr| match expr {
+                Expr::Generator { ranges, span, .. } if contains(span, ident_offset) => {
+                    bound.extend(ranges.iter().map(|(index, _)| index));
+                }
+                Expr::Match { arms, .. } => {
+                    for arm in arms {
+                        let in_arm = arm
+                            .constraints
+                            .iter()
+                            .chain([&arm.expr])
+                            .any(|expr| contains(&expr.span(), ident_offset));
+                        if in_arm {
+                            bound.extend(arm.binding.as_ref());
+                        }
+                    }
+                }
+                _ => {}
+            });
+        }
+    });
+    bound
+        .into_iter()
+        .rev()
+        .chain(locals(decl))
+        .find(|local| local.name == name)
+        .map(spanned)
+}
+
+/// The declaration of the name at `ident_offset`, or the name itself if it is a declaration.
+/// Only declarations in the same document are found; see [`path_at`] for the rest.
+pub fn get_definition(ast: &Ast, ident_offset: usize) -> Option<Spanned<String>> {
+    if let Some(name) = declared_names(ast)
+        .into_iter()
+        .find(|name| contains(&name.span, ident_offset))
+    {
+        return Some(spanned(name));
+    }
+    if let Some(member) = member_definition(ast, ident_offset) {
+        return Some(member);
+    }
+
+    let (path, at) = path_at(ast, ident_offset)?;
+    let segments = &path.segments[..=at];
+    if let [name] = segments {
+        if !path.absolute {
+            let local = ast
+                .iter()
+                .find(|decl| contains(&decl.span(), ident_offset))
+                .and_then(|decl| local_definition(decl, &name.name, ident_offset));
+            if local.is_some() {
+                return local;
             }
         }
-        Expr::Let(name, lhs, rest, name_span) => {
-            let new_decl = Vector::unit((name.clone(), name_span.clone()));
+    }
+    let name = segments
+        .iter()
+        .map(|segment| segment.name.as_str())
+        .collect::<Vec<_>>()
+        .join("::");
+    find_declaration(ast, &name)
+}
 
-            match get_definition_of_expr(lhs, definition_ass_list.clone(), ident_offset) {
-                (true, None) => {
-                    get_definition_of_expr(rest, new_decl + definition_ass_list, ident_offset)
+/// The names `use` declarations in `ast` bring into scope, with the path from the project's root
+/// module each one stands for. `module` is the module of the document.
+pub fn imports(ast: &Ast, module: &[String]) -> Vec<(String, Vec<String>)> {
+    fn flatten(tree: &UseTree, prefix: &[String], imports: &mut Vec<(String, Vec<String>)>) {
+        match tree {
+            UseTree::Name(name) if name.name == "self" => {
+                if let Some(last) = prefix.last() {
+                    imports.push((last.clone(), prefix.to_vec()));
+                }
+            }
+            UseTree::Name(name) => {
+                let mut path = prefix.to_vec();
+                path.push(name.name.clone());
+                imports.push((name.name.clone(), path));
+            }
+            UseTree::Alias { name, alias } => {
+                let mut path = prefix.to_vec();
+                path.push(name.name.clone());
+                imports.push((alias.name.clone(), path));
+            }
+            UseTree::Path {
+                prefix: head,
+                suffix,
+            } => {
+                let mut path = prefix.to_vec();
+                path.push(head.name.clone());
+                flatten(suffix, &path, imports);
+            }
+            UseTree::Group(trees) => {
+                for tree in trees {
+                    flatten(tree, prefix, imports);
                 }
-                (true, Some(value)) => (false, Some(value)),
-                (false, None) => (false, None),
-                (false, Some(value)) => (false, Some(value)),
-            }
-        }
-        Expr::Then(first, second) => {
-            match get_definition_of_expr(first, definition_ass_list.clone(), ident_offset) {
-                (true, None) => get_definition_of_expr(second, definition_ass_list, ident_offset),
-                (false, None) => (false, None),
-                (true, Some(value)) | (false, Some(value)) => (false, Some(value)),
-            }
-        }
-        Expr::Binary(lhs, _, rhs) => {
-            match get_definition_of_expr(lhs, definition_ass_list.clone(), ident_offset) {
-                (true, None) => get_definition_of_expr(rhs, definition_ass_list, ident_offset),
-                (false, None) => (false, None),
-                (true, Some(value)) | (false, Some(value)) => (false, Some(value)),
-            }
-        }
-        Expr::Call(callee, args) => {
-            match get_definition_of_expr(callee, definition_ass_list.clone(), ident_offset) {
-                (true, None) => {}
-                (true, Some(value)) => return (false, Some(value)),
-                (false, None) => return (false, None),
-                (false, Some(value)) => return (false, Some(value)),
-            }
-            for expr in &args.0 {
-                match get_definition_of_expr(expr, definition_ass_list.clone(), ident_offset) {
-                    (true, None) => continue,
-                    (true, Some(value)) => return (false, Some(value)),
-                    (false, None) => return (false, None),
-                    (false, Some(value)) => return (false, Some(value)),
+            }
+        }
+    }
+
+    let mut imports = vec![];
+    for decl in ast {
+        if let Decl::Use { absolute, tree, .. } = decl {
+            let prefix = if *absolute { &[] } else { module };
+            flatten(tree, prefix, &mut imports);
+        }
+    }
+    imports
+}
+
+/// The path of the `use` item at `offset`, from the project's root module and up to the segment
+/// the offset is in. `module` is the module of the document.
+pub fn use_path_at(ast: &Ast, module: &[String], offset: usize) -> Option<Vec<String>> {
+    fn find(tree: &UseTree, prefix: &mut Vec<String>, offset: usize) -> bool {
+        match tree {
+            UseTree::Name(name) | UseTree::Alias { name, .. } => {
+                let found = contains(&name.span, offset);
+                if found && name.name != "self" {
+                    prefix.push(name.name.clone());
                 }
+                found
             }
-            (true, None)
-        }
-        Expr::If(test, consequent, alternative) => {
-            match get_definition_of_expr(test, definition_ass_list.clone(), ident_offset) {
-                (true, None) => {}
-                (true, Some(value)) => return (false, Some(value)),
-                (false, None) => return (false, None),
-                (false, Some(value)) => return (false, Some(value)),
-            }
-            match get_definition_of_expr(consequent, definition_ass_list.clone(), ident_offset) {
-                (true, None) => {}
-                (true, Some(value)) => return (false, Some(value)),
-                (false, None) => return (false, None),
-                (false, Some(value)) => return (false, Some(value)),
-            }
-            match get_definition_of_expr(alternative, definition_ass_list, ident_offset) {
-                (true, None) => (true, None),
-                (true, Some(value)) => (false, Some(value)),
-                (false, None) => (false, None),
-                (false, Some(value)) => (false, Some(value)),
-            }
-        }
-        Expr::Print(expr) => get_definition_of_expr(expr, definition_ass_list, ident_offset),
-        Expr::List(lst) => {
-            for expr in lst {
-                match get_definition_of_expr(expr, definition_ass_list.clone(), ident_offset) {
-                    (true, None) => continue,
-                    (true, Some(value)) => return (false, Some(value)),
-                    (false, None) => return (false, None),
-                    (false, Some(value)) => return (false, Some(value)),
+            UseTree::Path {
+                prefix: head,
+                suffix,
+            } => {
+                prefix.push(head.name.clone());
+                contains(&head.span, offset) || find(suffix, prefix, offset) || {
+                    prefix.pop();
+                    false
                 }
             }
-            (true, None)
+            UseTree::Group(trees) => trees.iter().any(|tree| find(tree, prefix, offset)),
+        }
+    }
+
+    ast.iter().find_map(|decl| match decl {
+        Decl::Use {
+            absolute,
+            tree,
+            span,
+        } if contains(span, offset) => {
+            let mut path = if *absolute { vec![] } else { module.to_vec() };
+            find(tree, &mut path, offset).then_some(path)
+        }
+        _ => None,
+    })
+}
+
+/// A path as a path from the project's root module, for a document of module `module`. Paths
+/// that start with an imported name continue from the import.
+pub fn absolute_path(
+    absolute: bool,
+    segments: &[String],
+    module: &[String],
+    imports: &[(String, Vec<String>)],
+) -> Vec<String> {
+    if absolute {
+        return segments.to_vec();
+    }
+    let Some((first, rest)) = segments.split_first() else {
+        return module.to_vec();
+    };
+    match imports.iter().find(|(name, _)| name == first) {
+        Some((_, imported)) => imported.iter().chain(rest).cloned().collect(),
+        None => module.iter().chain(segments).cloned().collect(),
+    }
+}
+
+/// Call `f` on every expression of every predicate in `contract`, nested ones included.
+pub fn visit_contract(contract: &Contract, mut f: impl FnMut(ExprKey, &PintExpr)) {
+    let mut pending = contract
+        .preds
+        .keys()
+        .flat_map(|pred| contract.root_set(pred))
+        .collect::<Vec<_>>();
+    let mut seen = HashSet::new();
+    while let Some(key) = pending.pop() {
+        if !seen.insert(key) {
+            continue;
         }
+        let Some(expr) = key.try_get(contract) else {
+            continue;
+        };
+        // `replace_ref` is the only public way to reach every sub-expression.
+        expr.clone().replace_ref(|child| pending.push(*child));
+        f(key, expr);
     }
 }
+
+/// The absolute path pintc resolved the name at `offset` in `file` to, e.g. `::lib::N` for a
+/// use of a const `N` imported from module `lib`. pintc keeps the names of consts, union
+/// variants and predicate calls in expressions; types and macros are resolved away.
+pub fn contract_path(contract: &Contract, file: &std::path::Path, offset: usize) -> Option<String> {
+    let mut best: Option<(usize, String)> = None;
+    visit_contract(contract, |_, expr| {
+        let (path, span) = match expr {
+            PintExpr::Path(path, span) => (path, span),
+            PintExpr::UnionVariant {
+                path, path_span, ..
+            } => (path, path_span),
+            PintExpr::LocalPredicateCall {
+                predicate, span, ..
+            } => (predicate, span),
+            _ => return,
+        };
+        if span.context().as_ref() != file || !contains(&(span.start()..span.end()), offset) {
+            return;
+        }
+        let len = span.end() - span.start();
+        if best.as_ref().is_none_or(|(best_len, _)| len < *best_len) {
+            best = Some((len, path.clone()));
+        }
+    });
+    best.map(|(_, path)| path)
+}
@@ -1,5 +1,16 @@
 pub mod chumsky;
 pub mod completion;
+pub mod diagnostic;
+pub mod document_symbol;
+pub mod hover;
+pub mod inlay_hint;
+pub mod intrinsics;
 pub mod jump_definition;
+pub mod position;
+pub mod project;
 pub mod reference;
+pub mod rename;
 pub mod semantic_token;
+pub mod snapshot;
+pub mod snippet;
+pub mod workspace_symbol;
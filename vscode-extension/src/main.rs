@@ -1,51 +1,130 @@
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use std::fs::File;
-use std::io::Write;
-use fxhash::FxBuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use std::fs;
+use std::io;
+use fxhash::FxHashMap;
 use pintc::error::Handler;
+use pintc::predicate::Contract;
+use std::panic::{self, AssertUnwindSafe};
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use pint_language_server::chumsky::{
-    type_inference, ImCompleteSemanticToken,
+    parse, Ast, Ident, ImCompleteSemanticToken, Span, PRIMITIVE_TYPES,
 };
-use pint_language_server::completion::completion;
-use pint_language_server::jump_definition::get_definition;
-use pint_language_server::reference::get_reference;
-use pint_language_server::semantic_token::{semantic_token_from_ast, LEGEND_TYPE};
+use pint_language_server::completion::{
+    completion, completion_context, declaration_details, enclosing_predicate, keywords_at,
+    local_declaration, local_tuple_fields, module_items, receiver_name, storage_fields,
+    tuple_fields, type_completion, union_variants,
+    CompletionContext, CompletionData, Details, ImCompleteCompletionItem,
+};
+use pint_language_server::diagnostic::{pintc_diagnostic, Report, SourceSpan};
+use pint_language_server::document_symbol::{document_symbols, ImCompleteDocumentSymbol};
+use pint_language_server::hover::{expr_at, hover_markdown, let_signature, signature};
+use pint_language_server::inlay_hint::{let_type_hints, parameter_hints};
+use pint_language_server::intrinsics::{call_at, Intrinsic};
+use pint_language_server::jump_definition::{
+    absolute_path, contract_path, find_declaration, get_definition, imports, path_at,
+    use_path_at,
+};
+use pint_language_server::position::Encoding;
+use pint_language_server::rename::{
+    check_name, clashes, item_references, symbol_at, with_sigil, word_at, Symbol,
+};
+use pint_language_server::reference::{
+    get_reference, storage_accesses, storage_declaration, storage_field_at, storage_references,
+};
+use pint_language_server::project::{
+    find_manifests, interface_source, Project, ResolvedDependency,
+};
+use pint_language_server::semantic_token::{
+    semantic_token_edits, semantic_token_from_ast, LEGEND_MODIFIER, LEGEND_TYPE,
+};
+use pint_language_server::snapshot::{check_version, Edit, Snapshot};
+use pint_language_server::snippet::snippets_at;
+use pint_language_server::workspace_symbol::{fuzzy_search, searchable_symbols};
 use ropey::Rope;
-use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tower_lsp::jsonrpc::Result;
-use tower_lsp::lsp_types::notification::Notification;
+use tower_lsp::jsonrpc::{Error, Result};
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer, LspService, Server};
 #[derive(Debug)]
 struct Backend {
     client: Client,
-    ast_map: DashMap<String, pintc::predicate::Contract>,
+    /// The last analysis of each open document that produced a contract.
+    ast_map: DashMap<String, Snapshot>,
+    /// The syntax tree of each open document, kept even when the text does not compile.
+    syntax_map: DashMap<String, Ast>,
     document_map: DashMap<String, Rope>,
+    version_map: DashMap<String, i32>,
     semantic_token_map: DashMap<String, Vec<ImCompleteSemanticToken>>,
-
+    /// The semantic tokens last sent in full for each document, by their `result_id`, for
+    /// working out deltas against.
+    semantic_result_map: DashMap<String, (String, Vec<SemanticToken>)>,
+    /// The number the next `result_id` of semantic tokens is made from.
+    next_result_id: AtomicU64,
+    /// The project each open document was last analysed as part of.
+    project_map: DashMap<String, Project>,
+    /// Private directory that projects are mirrored into before pintc parses them.
+    scratch_dir: PathBuf,
+    /// The position encoding agreed with the client, set once `initialize` has run.
+    encoding: OnceLock<Encoding>,
+    /// The packages found in each workspace folder, for workspace/symbol.
+    workspace_map: DashMap<String, Vec<Project>>,
+    /// The workspace symbols of each source file, until the file changes.
+    symbol_map: DashMap<PathBuf, Vec<SymbolInformation>>,
+    /// Open documents whose buffer no longer matches the client's, after a change that could
+    /// not be applied.
+    desynced: DashSet<String>,
+    /// How many builds of each project, by root, have been asked for, so that a build that has
+    /// been overtaken by a newer edit can give up.
+    build_generation: DashMap<PathBuf, u64>,
+    /// The interface generated for each contract dependency, by root, with the fingerprint of
+    /// the sources it was generated from. `None` when the dependency had errors.
+    interface_map: Arc<DashMap<PathBuf, (u64, Option<String>)>>,
 }
 
+/// How long a build waits for further edits before it starts.
+const BUILD_DELAY: Duration = Duration::from_millis(150);
+
 #[tower_lsp::async_trait]
 impl LanguageServer for Backend {
-    async fn initialize(&self, _: InitializeParams) -> Result<InitializeResult> {
+    async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
+        let encoding = Encoding::negotiate(
+            params
+                .capabilities
+                .general
+                .as_ref()
+                .and_then(|general| general.position_encodings.as_deref()),
+        );
+        let _ = self.encoding.set(encoding);
+        #[allow(deprecated)]
+        let folders = params
+            .workspace_folders
+            .map(|folders| folders.into_iter().map(|folder| folder.uri).collect())
+            .or_else(|| params.root_uri.map(|root| vec![root]))
+            .unwrap_or_default();
+        // The folders are searched for packages once initialization is done, so the client does
+        // not wait on it.
+        for folder in folders {
+            self.workspace_map.insert(folder.to_string(), vec![]);
+        }
         Ok(InitializeResult {
             server_info: None,
             offset_encoding: None,
             capabilities: ServerCapabilities {
+                position_encoding: Some(encoding.kind()),
                 inlay_hint_provider: Some(OneOf::Left(true)),
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::FULL,
+                    TextDocumentSyncKind::INCREMENTAL,
                 )),
                 completion_provider: Some(CompletionOptions {
-                    resolve_provider: Some(false),
-                    trigger_characters: Some(vec!["".to_string()]),
+                    resolve_provider: Some(true),
+                    trigger_characters: Some(vec![":".to_string(), ".".to_string()]),
                     work_done_progress_options: Default::default(),
                     all_commit_characters: None,
                     completion_item: None,
@@ -68,7 +147,7 @@ impl LanguageServer for Backend {
                             text_document_registration_options: {
                                 TextDocumentRegistrationOptions {
                                     document_selector: Some(vec![DocumentFilter {
-                                        language: Some("nrs".to_string()),
+                                        language: Some("pint".to_string()),
                                         scheme: Some("file".to_string()),
                                         pattern: None,
                                     }]),
@@ -78,19 +157,32 @@ impl LanguageServer for Backend {
                                 work_done_progress_options: WorkDoneProgressOptions::default(),
                                 legend: SemanticTokensLegend {
                                     token_types: LEGEND_TYPE.into(),
-                                    token_modifiers: vec![],
+                                    token_modifiers: LEGEND_MODIFIER.into(),
                                 },
                                 range: Some(true),
-                                full: Some(SemanticTokensFullOptions::Bool(true)),
+                                full: Some(SemanticTokensFullOptions::Delta {
+                                    delta: Some(true),
+                                }),
                             },
                             static_registration_options: StaticRegistrationOptions::default(),
                         },
                     ),
                 ),
                 // definition: Some(GotoCapability::default()),
+                signature_help_provider: Some(SignatureHelpOptions {
+                    trigger_characters: Some(vec!["(".to_string(), ",".to_string()]),
+                    retrigger_characters: None,
+                    work_done_progress_options: Default::default(),
+                }),
                 definition_provider: Some(OneOf::Left(true)),
                 references_provider: Some(OneOf::Left(true)),
-                rename_provider: Some(OneOf::Left(true)),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                document_symbol_provider: Some(OneOf::Left(true)),
+                workspace_symbol_provider: Some(OneOf::Left(true)),
+                rename_provider: Some(OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    work_done_progress_options: Default::default(),
+                })),
                 ..ServerCapabilities::default()
             },
         })
@@ -99,9 +191,13 @@ impl LanguageServer for Backend {
         self.client
             .log_message(MessageType::INFO, "initialized!")
             .await;
+        for folder in self.workspace_folders() {
+            self.index_folder(&folder).await;
+        }
     }
 
     async fn shutdown(&self) -> Result<()> {
+        let _ = fs::remove_dir_all(&self.scratch_dir);
         Ok(())
     }
 
@@ -109,26 +205,84 @@ impl LanguageServer for Backend {
         self.client
             .log_message(MessageType::INFO, "file opened!")
             .await;
+        let uri = params.text_document.uri.to_string();
+        self.document_map
+            .insert(uri.clone(), Rope::from_str(&params.text_document.text));
+        self.desynced.remove(&uri);
+        self.version_map.insert(uri, params.text_document.version);
         self.on_change(TextDocumentItem {
             uri: params.text_document.uri,
-            text: params.text_document.text,
             version: params.text_document.version,
-        }).await
-
-        
+        })
+        .await
     }
 
-    async fn did_change(&self, mut params: DidChangeTextDocumentParams) {
-        
-        self.client
-            .log_message(MessageType::INFO, "file CHANGED!")
-            .await;
+    async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        let uri = params.text_document.uri.to_string();
+        let version = params.text_document.version;
+        let Some(current) = self.version_map.get(&uri).map(|v| *v) else {
+            return;
+        };
+
+        // Everything before a change of the whole text is replaced by it, so the buffer can be
+        // brought back in sync from there whatever happened before.
+        let full_text = params
+            .content_changes
+            .iter()
+            .rposition(|change| change.range.is_none());
+        let changes = &params.content_changes[full_text.unwrap_or(0)..];
+
+        // Edits are only meaningful against the exact buffer they were computed from, so anything
+        // that does not follow on from the current version is dropped rather than applied.
+        if let Err(err) = check_version(current, version, full_text.is_some()) {
+            self.lose_sync(&uri, err).await;
+            return;
+        }
+        if full_text.is_none() && self.desynced.contains(&uri) {
+            self.client
+                .log_message(
+                    MessageType::WARNING,
+                    format!("ignoring change to {uri} until it is back in sync"),
+                )
+                .await;
+            return;
+        }
+
+        // The batch is applied to a copy, so a change that fails leaves the buffer and the edits
+        // since the snapshot as the client last had them rather than half applied.
+        let Some(mut rope) = self.document_map.get(&uri).map(|rope| rope.clone()) else {
+            return;
+        };
+        let applied: std::result::Result<Vec<Edit>, String> = changes
+            .iter()
+            .map(|change| apply_change(&mut rope, change, self.encoding()))
+            .collect();
+        let edits = match applied {
+            Ok(edits) => edits,
+            Err(err) => {
+                self.lose_sync(&uri, err).await;
+                return;
+            }
+        };
+        self.desynced.remove(&uri);
+        if let Some(mut snapshot) = self.ast_map.get_mut(&uri) {
+            for edit in edits {
+                snapshot.edits.push(edit);
+            }
+        }
+        self.document_map.insert(uri.clone(), rope);
+        self.version_map.insert(uri, version);
+
+        // Requests that come in after the change must see it, so nothing waits before the text
+        // and its syntax tree are up to date.
         self.on_change(TextDocumentItem {
             uri: params.text_document.uri,
-            text: std::mem::take(&mut params.content_changes[0].text),
-            version: params.text_document.version,
+            version,
         })
-        .await
+        .await;
+        self.client
+            .log_message(MessageType::INFO, "file CHANGED!")
+            .await;
     }
 
     async fn did_save(&self, _: DidSaveTextDocumentParams) {
@@ -136,21 +290,121 @@ impl LanguageServer for Backend {
             .log_message(MessageType::INFO, "file saved!")
             .await;
     }
-    async fn did_close(&self, _: DidCloseTextDocumentParams) {
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
         self.client
             .log_message(MessageType::INFO, "file closed!")
             .await;
+        let uri = params.text_document.uri.to_string();
+        self.document_map.remove(&uri);
+        self.version_map.remove(&uri);
+        self.ast_map.remove(&uri);
+        self.syntax_map.remove(&uri);
+        self.semantic_token_map.remove(&uri);
+        self.semantic_result_map.remove(&uri);
+        self.desynced.remove(&uri);
+        // Symbols come from the saved file again once it is closed.
+        if let Ok(path) = params.text_document.uri.to_file_path() {
+            self.symbol_map.remove(&path);
+        }
+        // The mirror of a project is only needed while one of its files is open.
+        if let Some((_, project)) = self.project_map.remove(&uri) {
+            let in_use = self
+                .project_map
+                .iter()
+                .any(|entry| entry.value().staged_root == project.staged_root);
+            if !in_use {
+                let _ = fs::remove_dir_all(&project.staged_root);
+            }
+        }
+    }
+
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> Result<Option<GotoDefinitionResponse>> {
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        let definition = || -> Option<Location> {
+            let encoding = self.encoding();
+            // Cloned, as the declaration may be looked up in another open document.
+            let rope = self.document_map.get(&uri.to_string())?.clone();
+            let offset = encoding.offset(&rope, position)?;
+            let ast = self.syntax_map.get(&uri.to_string())?;
+            if let Some((_, span)) = get_definition(&ast, offset) {
+                return Some(Location::new(uri.clone(), encoding.range(&rope, &span)?));
+            }
+
+            drop(ast);
+            let project = self.project_map.get(&uri.to_string())?.clone();
+            let target = self.item_path(&uri, &project, offset)?;
+            self.declaration(&project, &target)
+        }();
+        Ok(definition.map(GotoDefinitionResponse::Scalar))
     }
 
-    // TODO:    //async fn goto_definition(
-    // TODO:     //async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+        let uri = params.text_document_position.text_document.uri;
+        let position = params.text_document_position.position;
+        let include_declaration = params.context.include_declaration;
+        let references = || -> Option<Vec<Location>> {
+            let encoding = self.encoding();
+            let rope = self.document_map.get(&uri.to_string())?.clone();
+            let offset = encoding.offset(&rope, position)?;
+            let ast = self.syntax_map.get(&uri.to_string())?;
+            if let Some(field) = storage_field_at(&ast, offset) {
+                drop(ast);
+                return self.storage_references(&uri, &field, include_declaration);
+            }
+            let references = get_reference(&ast, offset, include_declaration)
+                .into_iter()
+                .filter_map(|(_, span)| Some(Location::new(uri.clone(), encoding.range(&rope, &span)?)))
+                .collect();
+            Some(references)
+        }();
+        Ok(references)
+    }
 
 
-   async fn semantic_tokens_full(
-       &self,
-       params: SemanticTokensParams,
-   ) -> Result<Option<SemanticTokensResult>> {
-        Ok(None)
+    async fn semantic_tokens_full(
+        &self,
+        params: SemanticTokensParams,
+    ) -> Result<Option<SemanticTokensResult>> {
+        let uri = params.text_document.uri.to_string();
+        let Some(data) = self.semantic_tokens(&uri, None) else {
+            return Ok(None);
+        };
+        let result_id = self.remember_semantic_tokens(&uri, &data);
+        Ok(Some(SemanticTokensResult::Tokens(SemanticTokens {
+            result_id: Some(result_id),
+            data,
+        })))
+    }
+
+    async fn semantic_tokens_full_delta(
+        &self,
+        params: SemanticTokensDeltaParams,
+    ) -> Result<Option<SemanticTokensFullDeltaResult>> {
+        let uri = params.text_document.uri.to_string();
+        let Some(data) = self.semantic_tokens(&uri, None) else {
+            return Ok(None);
+        };
+        let previous = self
+            .semantic_result_map
+            .get(&uri)
+            .filter(|entry| entry.0 == params.previous_result_id)
+            .map(|entry| semantic_token_edits(&entry.1, &data));
+        let result_id = self.remember_semantic_tokens(&uri, &data);
+        // Without the tokens the client has, all of them are sent again.
+        Ok(Some(match previous {
+            Some(edits) => SemanticTokensFullDeltaResult::TokensDelta(SemanticTokensDelta {
+                result_id: Some(result_id),
+                edits,
+            }),
+            None => SemanticTokensFullDeltaResult::Tokens(SemanticTokens {
+                result_id: Some(result_id),
+                data,
+            }),
+        }))
     }
 
     async fn semantic_tokens_range(
@@ -158,52 +412,54 @@ impl LanguageServer for Backend {
         params: SemanticTokensRangeParams,
     ) -> Result<Option<SemanticTokensRangeResult>> {
         let uri = params.text_document.uri.to_string();
-        let semantic_tokens = || -> Option<Vec<SemanticToken>> {
-            let im_complete_tokens = self.semantic_token_map.get(&uri)?;
-            let rope = self.document_map.get(&uri)?;
-            let mut pre_line = 0;
-            let mut pre_start = 0;
-            let semantic_tokens = im_complete_tokens
-                .iter()
-                .filter_map(|token| {
-                    let line = rope.try_byte_to_line(token.start).ok()? as u32;
-                    let first = rope.try_line_to_char(line as usize).ok()? as u32;
-                    let start = rope.try_byte_to_char(token.start).ok()? as u32 - first;
-                    let ret = Some(SemanticToken {
-                        delta_line: line - pre_line,
-                        delta_start: if start >= pre_start {
-                            start - pre_start
-                        } else {
-                            start
-                        },
-                        length: token.length as u32,
-                        token_type: token.token_type as u32,
-                        token_modifiers_bitset: 0,
-                    });
-                    pre_line = line;
-                    pre_start = start;
-                    ret
-                })
-                .collect::<Vec<_>>();
-            Some(semantic_tokens)
-        }();
-        if let Some(semantic_token) = semantic_tokens {
-            return Ok(Some(SemanticTokensRangeResult::Tokens(SemanticTokens {
+        Ok(self.semantic_tokens(&uri, Some(params.range)).map(|data| {
+            SemanticTokensRangeResult::Tokens(SemanticTokens {
                 result_id: None,
-                data: semantic_token,
-            })));
-        }
-        Ok(None)
+                data,
+            })
+        }))
     }
 
+    async fn inlay_hint(&self, params: InlayHintParams) -> Result<Option<Vec<InlayHint>>> {
+        let uri = params.text_document.uri;
+        let hints = || -> Option<Vec<InlayHint>> {
+            let encoding = self.encoding();
+            let rope = self.document_map.get(&uri.to_string())?.clone();
+            let ast = self.syntax_map.get(&uri.to_string())?.clone();
+            let start = encoding.clamped_offset(&rope, params.range.start);
+            let end = encoding.clamped_offset(&rope, params.range.end);
 
-   async fn inlay_hint(
-       &self,
-       params: tower_lsp::lsp_types::InlayHintParams,
-   ) -> Result<Option<Vec<InlayHint>>> {
-   
-    Ok(None)
-   }
+            let mut hints = parameter_hints(&ast);
+            // Types are only known from the last contract pintc built.
+            let types = self.ast_map.get(&uri.to_string()).and_then(|snapshot| {
+                let project = self.project_map.get(&uri.to_string())?.clone();
+                let staged = project.staged_path(&uri.to_file_path().ok()?)?;
+                Some(let_type_hints(&ast, &snapshot, &staged))
+            });
+            hints.extend(types.into_iter().flatten());
+            hints.sort_by_key(|hint| hint.offset);
+            Some(
+                hints
+                    .into_iter()
+                    .filter(|hint| start <= hint.offset && hint.offset <= end)
+                    .filter_map(|hint| {
+                        let padding = hint.kind == InlayHintKind::PARAMETER;
+                        Some(InlayHint {
+                            position: encoding.position(&rope, hint.offset)?,
+                            label: InlayHintLabel::String(hint.label),
+                            kind: Some(hint.kind),
+                            text_edits: None,
+                            tooltip: None,
+                            padding_left: None,
+                            padding_right: padding.then_some(true),
+                            data: None,
+                        })
+                    })
+                    .collect(),
+            )
+        }();
+        Ok(hints)
+    }
 
    async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
        self.client
@@ -211,102 +467,468 @@ impl LanguageServer for Backend {
            .await;
        let uri = params.text_document_position.text_document.uri;
        let position = params.text_document_position.position;
-       let completions = || -> Option<Vec<CompletionItem>> {
-           let rope = self.document_map.get(&uri.to_string())?;
-           let ast = self.ast_map.get(&uri.to_string())?;
-           let char = rope.try_line_to_char(position.line as usize).ok()?;
-           let offset = char + position.character as usize;
-           
-
-           let completions = completion(&ast, offset);
+       let completions = || -> Option<(bool, Vec<CompletionItem>)> {
+           let rope = self.document_map.get(&uri.to_string())?.clone();
+           let current_offset = self.encoding().offset(&rope, position)?;
+           let file = uri.to_file_path().ok()?;
+           let project = self.project_map.get(&uri.to_string())?.clone();
+           let text = rope.to_string();
+           let context = match self.syntax_map.get(&uri.to_string()) {
+               Some(ast) => completion_context(&ast, &text, current_offset),
+               None => completion_context(&Ast::new(), &text, current_offset),
+           };
+           // What each item names, so that resolving it can find its declaration.
+           let path_data = |segments: Vec<String>| {
+               let absolute = matches!(context, CompletionContext::Path { absolute: true, .. });
+               serde_json::to_value(CompletionData::Path {
+                   uri: uri.clone(),
+                   absolute,
+                   segments,
+               })
+               .ok()
+           };
+           let (stale, completions) = match context.clone() {
+               CompletionContext::None => (false, vec![]),
+               CompletionContext::Type => {
+                   let ast = self.syntax_map.get(&uri.to_string())?;
+                   (false, type_completion(&ast))
+               }
+               CompletionContext::Path { absolute, segments } => (
+                   false,
+                   self.path_completion(&uri, &project, absolute, &segments)
+                       .unwrap_or_default(),
+               ),
+               CompletionContext::Member { receiver_end } => {
+                   let snapshot = self.ast_map.get(&uri.to_string())?;
+                   let version = self.version_map.get(&uri.to_string()).map(|v| *v)?;
+                   let staged = project.staged_path(&file)?;
+                   // A local is found through its declaration, as the contract may predate the
+                   // receiver. Other receivers have to be in the contract already.
+                   let local = receiver_name(&text, receiver_end).and_then(|name| {
+                       let ast = self.syntax_map.get(&uri.to_string())?;
+                       let span = local_declaration(&ast, receiver_end, name)?;
+                       Some(Ident {
+                           name: name.to_string(),
+                           span,
+                       })
+                   });
+                   let mut fields = local
+                       .map(|name| local_tuple_fields(&snapshot, &staged, &name))
+                       .unwrap_or_default();
+                   if fields.is_empty() {
+                       let receiver_end = snapshot.edits.to_snapshot(receiver_end);
+                       fields = tuple_fields(&snapshot.contract, &staged, receiver_end);
+                   }
+                   (snapshot.is_stale(version), fields)
+               }
+               CompletionContext::Name => {
+                   let mut items = self
+                       .syntax_map
+                       .get(&uri.to_string())
+                       .map(|ast| {
+                           let mut items = snippets_at(&ast, &text, current_offset)
+                               .iter()
+                               .map(ImCompleteCompletionItem::Snippet)
+                               .collect::<Vec<_>>();
+                           items.extend(keywords_at(&ast, &text, current_offset));
+                           items
+                       })
+                       .unwrap_or_default();
+                   let snapshot = self.ast_map.get(&uri.to_string());
+                   let version = self.version_map.get(&uri.to_string()).map(|v| *v);
+                   let staged = project.staged_path(&file);
+                   let stale = match (snapshot, version, staged) {
+                       (Some(snapshot), Some(version), Some(staged)) => {
+                           // The contract may predate the text being typed, so look the cursor
+                           // up where it was when the contract was built.
+                           let offset = snapshot.edits.to_snapshot(current_offset);
+                           let predicate = self.syntax_map.get(&uri.to_string()).and_then(|ast| {
+                               let name = enclosing_predicate(&ast, current_offset)?;
+                               let mut path = project.module_path(&file)?;
+                               path.push(name.name.clone());
+                               Some(format!("::{}", path.join("::")))
+                           });
+                           items.extend(completion(
+                               &snapshot.contract,
+                               &staged,
+                               predicate.as_deref(),
+                               offset,
+                           ));
+                           snapshot.is_stale(version)
+                       }
+                       _ => false,
+                   };
+                   (stale, items)
+               }
+           };
 
            let mut ret = Vec::with_capacity(completions.len());
-           for (_, item) in completions {
+           for item in completions {
                match item {
-                   pint_language_server::completion::ImCompleteCompletionItem::Variable(var) => {
+                   ImCompleteCompletionItem::Variable { name, ty } => {
+                       let data = serde_json::to_value(CompletionData::Local {
+                           uri: uri.clone(),
+                           offset: current_offset,
+                           name: name.clone(),
+                       });
                        ret.push(CompletionItem {
-                           label: var.clone(),
-                           insert_text: Some(var.clone()),
+                           label: name.clone(),
+                           insert_text: Some(name.clone()),
                            kind: Some(CompletionItemKind::VARIABLE),
-                           detail: Some(var),
+                           detail: ty,
                            sort_text: Some("c".to_string()),
+                           data: data.ok(),
                            ..Default::default()
                        });
                    },
 
-                   pint_language_server::completion::ImCompleteCompletionItem::Type(var) => {
+                   ImCompleteCompletionItem::Type(var) => {
+                    let data = if PRIMITIVE_TYPES.contains(&var.as_str()) {
+                        None
+                    } else {
+                        path_data(vec![var.clone()])
+                    };
                     ret.push(CompletionItem {
                         label: var.clone(),
-                        insert_text: Some(var.clone()),
+                        insert_text: Some(var),
                         kind: Some(CompletionItemKind::CLASS),
-                        detail: Some(var),
                         sort_text: Some("b".to_string()),
+                        data,
                         ..Default::default()
                     });
                 },
 
-                pint_language_server::completion::ImCompleteCompletionItem::Keyword(var) => {
+                ImCompleteCompletionItem::Keyword(var) => {
                     ret.push(CompletionItem {
                         label: var.clone(),
-                        insert_text: Some(var.clone()),
+                        insert_text: Some(var),
                         kind: Some(CompletionItemKind::KEYWORD),
-                        detail: Some(var),
                         sort_text: Some("a".to_string()),
                         ..Default::default()
                     });
                 }
 
-                pint_language_server::completion::ImCompleteCompletionItem::StorageVariable(var) => {
+                ImCompleteCompletionItem::Intrinsic(intrinsic) => {
+                    ret.push(CompletionItem {
+                        label: intrinsic.name.to_string(),
+                        insert_text: Some(intrinsic.snippet()),
+                        insert_text_format: Some(InsertTextFormat::SNIPPET),
+                        kind: Some(CompletionItemKind::FUNCTION),
+                        detail: Some(intrinsic.signature()),
+                        documentation: Some(Documentation::String(intrinsic.doc.to_string())),
+                        sort_text: Some("e".to_string()),
+                        ..Default::default()
+                    });
+                }
+
+                ImCompleteCompletionItem::Snippet(snippet) => {
+                    ret.push(CompletionItem {
+                        label: snippet.label.to_string(),
+                        insert_text: Some(snippet.body.to_string()),
+                        insert_text_format: Some(InsertTextFormat::SNIPPET),
+                        kind: Some(CompletionItemKind::SNIPPET),
+                        detail: Some(snippet.detail.to_string()),
+                        sort_text: Some("a".to_string()),
+                        ..Default::default()
+                    });
+                }
+
+                ImCompleteCompletionItem::Member { name, kind, detail } => {
+                    let data = match &context {
+                        CompletionContext::Path { segments, .. }
+                            if kind != CompletionItemKind::MODULE =>
+                        {
+                            let mut segments = segments.clone();
+                            segments.push(name.clone());
+                            path_data(segments)
+                        }
+                        _ => None,
+                    };
+                    ret.push(CompletionItem {
+                        label: name.clone(),
+                        insert_text: Some(name),
+                        kind: Some(kind),
+                        detail,
+                        data,
+                        ..Default::default()
+                    });
+                }
+
+                ImCompleteCompletionItem::StorageVariable(var) => {
+                    let data = path_data(var.split("::").map(str::to_string).collect());
                     ret.push(CompletionItem {
                         label: var.clone(),
-                        insert_text: Some(var.clone()),
+                        insert_text: Some(var),
                         kind: Some(CompletionItemKind::FIELD),
-                        detail: Some(var),
                         sort_text: Some("d".to_string()),
+                        data,
                         ..Default::default()
                     });
                 }
                }
            }
-           Some(ret)
+           Some((stale, ret))
        }();
-       
-       Ok(completions.map(CompletionResponse::Array))
+
+       // Results from an outdated contract are marked incomplete so the client asks again as
+       // typing continues.
+       Ok(completions.map(|(stale, items)| {
+           CompletionResponse::List(CompletionList {
+               is_incomplete: stale,
+               items,
+           })
+       }))
    }
 
-//   async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
-//       let workspace_edit = || -> Option<WorkspaceEdit> {
-//           let uri = params.text_document_position.text_document.uri;
-//           let ast = self.ast_map.get(&uri.to_string())?;
-//           let rope = self.document_map.get(&uri.to_string())?;
-//
-//           let position = params.text_document_position.position;
-//           let char = rope.try_line_to_char(position.line as usize).ok()?;
-//           let offset = char + position.character as usize;
-//           let reference_list = get_reference(&ast, offset, true);
-//           let new_name = params.new_name;
-//           if !reference_list.is_empty() {
-//               let edit_list = reference_list
-//                   .into_iter()
-//                   .filter_map(|(_, range)| {
-//                       let start_position = offset_to_position(range.start, &rope)?;
-//                       let end_position = offset_to_position(range.end, &rope)?;
-//                       Some(TextEdit::new(
-//                           Range::new(start_position, end_position),
-//                           new_name.clone(),
-//                       ))
-//                   })
-//                   .collect::<Vec<_>>();
-//               let mut map = HashMap::new();
-//               map.insert(uri, edit_list);
-//               let workspace_edit = WorkspaceEdit::new(map);
-//               Some(workspace_edit)
-//           } else {
-//               None
-//           }
-//       }();
-//       Ok(workspace_edit)
-//   }
+    async fn completion_resolve(&self, mut item: CompletionItem) -> Result<CompletionItem> {
+        let data = item
+            .data
+            .clone()
+            .and_then(|data| serde_json::from_value::<CompletionData>(data).ok());
+        let Some(details) = data.and_then(|data| self.completion_details(data)) else {
+            return Ok(item);
+        };
+
+        let mut documentation = format!("```pint\n{}\n```", details.source);
+        if let Some(doc) = &details.doc {
+            documentation.push_str(&format!("\n\n{doc}"));
+        }
+        if let Some(index) = details.storage_key {
+            documentation.push_str(&format!("\n\nStorage key: `[{index}]`"));
+        }
+        if item.detail.is_none() {
+            item.detail = details.ty;
+        }
+        item.documentation = Some(Documentation::MarkupContent(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: documentation,
+        }));
+        Ok(item)
+    }
+
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        let hover = || -> Option<Hover> {
+            let encoding = self.encoding();
+            let rope = self.document_map.get(&uri.to_string())?.clone();
+            let offset = encoding.offset(&rope, position)?;
+            let project = self.project_map.get(&uri.to_string())?.clone();
+            let file = uri.to_file_path().ok()?;
+
+            // Types come from the contract, looked up where the cursor was when it was built.
+            let version = self.version_map.get(&uri.to_string()).map(|v| *v);
+            let mut stale = false;
+            let expr = self.ast_map.get(&uri.to_string()).and_then(|snapshot| {
+                stale = version.is_some_and(|version| snapshot.is_stale(version));
+                let staged = project.staged_path(&file)?;
+                let mut expr = expr_at(
+                    &snapshot.contract,
+                    &staged,
+                    snapshot.edits.to_snapshot(offset),
+                )?;
+                expr.span = snapshot.edits.to_current(expr.span.start)?
+                    ..snapshot.edits.to_current(expr.span.end)?;
+                Some(expr)
+            });
+            let word = word_at(&rope.to_string(), offset);
+            let declaration = word
+                .as_ref()
+                .and_then(|_| self.hover_declaration(&uri, &project, offset));
+            let mutable = expr.as_ref().and_then(|expr| expr.mutable);
+            // Whether any of the hover comes from the contract rather than the text.
+            let mut from_contract = mutable.is_some();
+
+            let (code, doc, span) = match (word, declaration) {
+                (Some(word), Some((details, signature))) => {
+                    let inferred = signature
+                        .is_none()
+                        .then(|| self.inferred_let(&uri, &project, offset))
+                        .flatten();
+                    from_contract |= inferred.is_some();
+                    let signature = signature.or(inferred);
+                    let name = rope.byte_slice(word.clone()).to_string();
+                    let expr_ty = expr
+                        .as_ref()
+                        // A path or storage access ends with the name it is typed by.
+                        .filter(|expr| expr.span.end == word.end)
+                        .and_then(|expr| expr.ty.clone());
+                    from_contract |= signature.is_none() && expr_ty.is_some();
+                    let ty = expr_ty.or(details.ty);
+                    let code = match (signature, ty) {
+                        (Some(signature), _) => signature,
+                        (None, Some(ty)) if mutable.is_some() => format!("storage::{name}: {ty}"),
+                        (None, Some(ty)) => format!("{name}: {ty}"),
+                        (None, None) => details.source,
+                    };
+                    (code, details.doc, word)
+                }
+                _ => {
+                    let expr = expr.as_ref()?;
+                    from_contract = true;
+                    (expr.ty.clone()?, None, expr.span.clone())
+                }
+            };
+            Some(Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: hover_markdown(
+                        &code,
+                        doc.as_deref(),
+                        mutable,
+                        stale && from_contract,
+                    ),
+                }),
+                range: encoding.range(&rope, &span),
+            })
+        }();
+        Ok(hover)
+    }
+
+    async fn document_symbol(
+        &self,
+        params: DocumentSymbolParams,
+    ) -> Result<Option<DocumentSymbolResponse>> {
+        let uri = params.text_document.uri;
+        let symbols = || -> Option<Vec<DocumentSymbol>> {
+            let rope = self.document_map.get(&uri.to_string())?.clone();
+            let ast = self.syntax_map.get(&uri.to_string())?;
+            Some(
+                document_symbols(&ast)
+                    .into_iter()
+                    .filter_map(|symbol| self.document_symbol_from(&rope, symbol))
+                    .collect(),
+            )
+        }();
+        Ok(symbols.map(DocumentSymbolResponse::Nested))
+    }
+
+    async fn signature_help(&self, params: SignatureHelpParams) -> Result<Option<SignatureHelp>> {
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        let signature_help = || -> Option<SignatureHelp> {
+            let rope = self.document_map.get(&uri.to_string())?;
+            let offset = self.encoding().offset(&rope, position)?;
+            let text = rope.to_string();
+            let (name, argument) = call_at(&text, offset)?;
+            let intrinsic = Intrinsic::find(name)?;
+            let parameters = intrinsic
+                .param_offsets()
+                .into_iter()
+                .map(|(start, end)| ParameterInformation {
+                    label: ParameterLabel::LabelOffsets([start as u32, end as u32]),
+                    documentation: None,
+                })
+                .collect();
+            Some(SignatureHelp {
+                signatures: vec![SignatureInformation {
+                    label: intrinsic.signature(),
+                    documentation: Some(Documentation::String(intrinsic.doc.to_string())),
+                    parameters: Some(parameters),
+                    active_parameter: None,
+                }],
+                active_signature: Some(0),
+                active_parameter: Some(argument as u32),
+            })
+        }();
+        Ok(signature_help)
+    }
+
+    async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> Result<Option<PrepareRenameResponse>> {
+        let (symbol, range) = self
+            .rename_target(&params.text_document.uri, params.position)
+            .map_err(Error::invalid_params)?;
+        Ok(Some(PrepareRenameResponse::RangeWithPlaceholder {
+            range,
+            placeholder: symbol.name().to_string(),
+        }))
+    }
+
+    async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        let uri = params.text_document_position.text_document.uri;
+        let position = params.text_document_position.position;
+        let (symbol, _) = self
+            .rename_target(&uri, position)
+            .map_err(Error::invalid_params)?;
+        let new_name = with_sigil(symbol.name(), &params.new_name);
+        check_name(&new_name).map_err(Error::invalid_params)?;
+        let clash = || Error::invalid_params(format!("`{new_name}` is already defined"));
+
+        let encoding = self.encoding();
+        let project = self.project_map.get(&uri.to_string()).map(|p| p.clone());
+        let Some((project, file)) = project.zip(uri.to_file_path().ok()) else {
+            return Ok(None);
+        };
+        let Some(module) = project.module_path(&file) else {
+            return Ok(None);
+        };
+        let locations = match &symbol {
+            Symbol::Local((_, declaration)) => {
+                let Some((_, rope, ast)) = self.source(&file) else {
+                    return Ok(None);
+                };
+                if clashes(&ast, &module, &symbol, &new_name) {
+                    return Err(clash());
+                }
+                get_reference(&ast, declaration.start, true)
+                    .into_iter()
+                    .filter_map(|(_, span)| Some(Location::new(uri.clone(), encoding.range(&rope, &span)?)))
+                    .collect()
+            }
+            Symbol::Storage(field) => {
+                let Some((_, _, ast)) = self.source(&file) else {
+                    return Ok(None);
+                };
+                if clashes(&ast, &module, &symbol, &new_name) {
+                    return Err(clash());
+                }
+                self.storage_references(&uri, field, true)
+                    .unwrap_or_default()
+            }
+            Symbol::Item(item) => {
+                // Every file of the package, including new ones that are only open so far.
+                let mut files = project.source_files();
+                files.extend(
+                    self.overlays()
+                        .into_keys()
+                        .filter(|file| project.contains(file) && !files.contains(file))
+                        .collect::<Vec<_>>(),
+                );
+                let mut locations = vec![];
+                for file in files {
+                    let Some((uri, rope, ast)) = self.source(&file) else {
+                        continue;
+                    };
+                    let Some(module) = project.module_path(&file) else {
+                        continue;
+                    };
+                    let spans = item_references(&ast, &module, item);
+                    if spans.is_empty() {
+                        continue;
+                    }
+                    if clashes(&ast, &module, &symbol, &new_name) {
+                        return Err(clash());
+                    }
+                    locations.extend(spans.iter().filter_map(|span| {
+                        Some(Location::new(uri.clone(), encoding.range(&rope, span)?))
+                    }));
+                }
+                locations
+            }
+        };
+
+        let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+        for location in locations {
+            changes
+                .entry(location.uri)
+                .or_default()
+                .push(TextEdit::new(location.range, new_name.clone()));
+        }
+        Ok(Some(WorkspaceEdit::new(changes)))
+    }
 
     async fn did_change_configuration(&self, _: DidChangeConfigurationParams) {
         self.client
@@ -314,16 +936,60 @@ impl LanguageServer for Backend {
             .await;
     }
 
-    async fn did_change_workspace_folders(&self, _: DidChangeWorkspaceFoldersParams) {
+    async fn did_change_workspace_folders(&self, params: DidChangeWorkspaceFoldersParams) {
         self.client
             .log_message(MessageType::INFO, "workspace folders changed!")
             .await;
+        for folder in params.event.removed {
+            self.workspace_map.remove(&folder.uri.to_string());
+        }
+        for folder in params.event.added {
+            self.workspace_map.insert(folder.uri.to_string(), vec![]);
+            self.index_folder(&folder.uri).await;
+        }
+    }
+
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> Result<Option<Vec<SymbolInformation>>> {
+        let mut symbols = vec![];
+        for project in self.workspace_projects() {
+            for file in project.source_files() {
+                if let Some(cached) = self.symbol_map.get(&file) {
+                    symbols.extend(cached.iter().cloned());
+                    continue;
+                }
+                let file_symbols = self.file_symbols(&project, &file);
+                symbols.extend(file_symbols.iter().cloned());
+                self.symbol_map.insert(file, file_symbols);
+            }
+        }
+        Ok(Some(fuzzy_search(&params.query, symbols, |symbol| {
+            &symbol.name
+        })))
     }
 
-    async fn did_change_watched_files(&self, _: DidChangeWatchedFilesParams) {
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
         self.client
             .log_message(MessageType::INFO, "watched files have changed!")
             .await;
+        let mut manifest_changed = false;
+        for change in params.changes {
+            let Ok(path) = change.uri.to_file_path() else {
+                continue;
+            };
+            manifest_changed |= path.file_name().is_some_and(|name| name == "pint.toml");
+            self.symbol_map.remove(&path);
+        }
+        // A manifest can add or drop packages and dependencies, or rename a package and so every
+        // container name in it, so the folders are indexed afresh.
+        if manifest_changed {
+            for folder in self.workspace_folders() {
+                self.index_folder(&folder).await;
+            }
+            self.symbol_map.clear();
+        }
     }
 
     async fn execute_command(&self, _: ExecuteCommandParams) -> Result<Option<Value>> {
@@ -340,105 +1006,884 @@ impl LanguageServer for Backend {
         Ok(None)
     }
 }
-#[derive(Debug, Deserialize, Serialize)]
-struct InlayHintParams {
-    path: String,
-}
-
-enum CustomNotification {}
-impl Notification for CustomNotification {
-    type Params = InlayHintParams;
-    const METHOD: &'static str = "custom/notification";
-}
 struct TextDocumentItem {
     uri: Url,
-    text: String,
     version: i32,
 }
 
+/// What a single pintc run over a project produced.
+struct ProjectAnalysis {
+    /// The type-checked contract, or the parsed one if type-checking failed.
+    ast: Option<Contract>,
+    type_checked: bool,
+    diagnostics: HashMap<PathBuf, Vec<Diagnostic>>,
+}
+
 struct NonSend {
-    name: String
+    /// How diagnostic ranges are reported to the client.
+    encoding: Encoding,
+    /// Interfaces of contract dependencies from earlier builds, see `Backend::interface_map`.
+    interfaces: Arc<DashMap<PathBuf, (u64, Option<String>)>>,
 }
 
 impl NonSend {
-    fn new() -> Self{
+    fn new(encoding: Encoding, interfaces: Arc<DashMap<PathBuf, (u64, Option<String>)>>) -> Self {
         NonSend {
-            name: "name".to_string()
+            encoding,
+            interfaces,
         }
     }
 
-    fn parse(&self, params: &TextDocumentItem, backend: &Backend) -> Vec<Diagnostic>{
+    fn parse(&self, project: &Project, overlays: &HashMap<PathBuf, String>) -> ProjectAnalysis {
+        let entry = project.root.join(&project.entry);
+
+        // pintc only parses from disk, so the project is mirrored into this server's scratch
+        // directory with the open buffers written over the saved files.
+        let mut sources = HashMap::new();
+        let mut problems = project.unresolved.clone();
+        if let Err(err) = self.stage(project, overlays, &mut sources, &mut problems) {
+            let message = format!(
+                "failed to stage {} for parsing: {err}",
+                project.root.display()
+            );
+            return ProjectAnalysis {
+                ast: None,
+                type_checked: false,
+                diagnostics: HashMap::from([(
+                    entry,
+                    vec![pintc_diagnostic(
+                        Range::default(),
+                        DiagnosticSeverity::ERROR,
+                        None,
+                        message,
+                    )],
+                )]),
+            };
+        }
 
-        let rope = ropey::Rope::from_str(&params.text);
         let handler = Handler::default();
-        //let path = Path::new("/home/javier/test.go");
-        //
-        let path = "/tmp/pint-lsp";
-        let mut file = File::create(path);
-    
-        // Write the string to the file
-        file.expect("failed ot open tmp file").write_all(params.text.as_bytes());
+        let dependency_entries = project.dependency_entries();
+        let dependencies: FxHashMap<&str, &Path> = dependency_entries
+            .iter()
+            .map(|(name, path)| (name.as_str(), path.as_path()))
+            .collect();
+
+        let ast =
+            pintc::parser::parse_project(&handler, &dependencies, &project.staged_entry()).ok();
+        // The semantic passes consume the contract, so they run on a copy and the parsed tree is
+        // kept when they fail.
+        let checked = ast.clone().and_then(|ast| self.check(ast, &handler));
+        let type_checked = checked.is_some();
+
+        let (errors, warnings) = handler.consume();
+        let reports = errors
+            .iter()
+            .map(Report::from_error)
+            .chain(warnings.iter().map(Report::from_warning));
+
+        let mut ropes: HashMap<PathBuf, Rope> = HashMap::new();
+        // Dependencies report their own errors when they are opened, only this project's files
+        // get diagnostics here.
+        let mut diagnostics: HashMap<PathBuf, Vec<Diagnostic>> = sources
+            .keys()
+            .filter(|path| project.contains(path))
+            .map(|path| (path.clone(), vec![]))
+            .collect();
+        for report in reports {
+            // Errors without a file (e.g. a missing entry point) are reported on the entry point.
+            let path = if report.span.file.as_os_str().is_empty() {
+                entry.clone()
+            } else {
+                match project.real_path(&report.span.file) {
+                    Some(path) if project.contains(&path) => path,
+                    _ => continue,
+                }
+            };
+            let Some(range) = source_range(&path, &report.span, &sources, &mut ropes, self.encoding) else {
+                continue;
+            };
+            let Ok(uri) = Url::from_file_path(&path) else {
+                continue;
+            };
+
+            // The primary label explains the error itself, the others become related
+            // information pointing at wherever they are, as do the note and help.
+            let mut message = report.message;
+            let mut related = vec![];
+            for (span, label) in report.labels {
+                if span == report.span {
+                    // pintc's labels often restate the message, so only what is new is kept.
+                    let (lower_message, lower_label) = (message.to_lowercase(), label.to_lowercase());
+                    if lower_label.contains(&lower_message) {
+                        message = label;
+                    } else if !lower_message.contains(&lower_label) {
+                        message = format!("{message}\n{label}");
+                    }
+                    continue;
+                }
+                let located = project.real_path(&span.file).and_then(|path| {
+                    let range = source_range(&path, &span, &sources, &mut ropes, self.encoding)?;
+                    Some(Location::new(Url::from_file_path(&path).ok()?, range))
+                });
+                if let Some(location) = located {
+                    related.push(DiagnosticRelatedInformation {
+                        location,
+                        message: label,
+                    });
+                }
+            }
+            for extra in [
+                report.note.map(|note| format!("note: {note}")),
+                report.help.map(|help| format!("help: {help}")),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                related.push(DiagnosticRelatedInformation {
+                    location: Location::new(uri.clone(), range),
+                    message: extra,
+                });
+            }
+
+            let mut diagnostic =
+                pintc_diagnostic(range, report.severity, Some(report.code), message);
+            if !related.is_empty() {
+                diagnostic.related_information = Some(related);
+            }
+            diagnostics.entry(path).or_default().push(diagnostic);
+        }
+
+        // Unusable dependencies are warnings: the imports that need them fail with errors of
+        // their own.
+        let entry_diagnostics = diagnostics.entry(entry).or_default();
+        for problem in problems {
+            entry_diagnostics.push(pintc_diagnostic(
+                Range::default(),
+                DiagnosticSeverity::WARNING,
+                Some("dependency".to_string()),
+                problem,
+            ));
+        }
+
+        ProjectAnalysis {
+            ast: checked.or(ast),
+            type_checked,
+            diagnostics,
+        }
+    }
+
+    /// Run the passes `pint build` runs after parsing, reporting their errors to `handler`.
+    /// Returns the type-checked contract if type-checking succeeded.
+    fn check(&self, contract: Contract, handler: &Handler) -> Option<Contract> {
+        // A bug in pintc must not take the server down with it.
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let checked = handler.scope(|handler| contract.type_check(handler)).ok()?;
+            // Flattening runs the array checks and lowers `if` and `match`, which is where some
+            // errors only show up, and the optimizer warns about constraints that can never hold.
+            // Their output no longer matches the source, so it is not kept.
+            if let Ok(flattened) = handler.scope(|handler| checked.clone().flatten(handler)) {
+                flattened.optimize(handler);
+            }
+            Some(checked)
+        }))
+        .unwrap_or(None)
+    }
+
+    /// Mirror `project` and everything it depends on into the scratch directory, collecting the
+    /// staged text of each source by real path. Dependencies that cannot be used are described in
+    /// `problems`.
+    fn stage(
+        &self,
+        project: &Project,
+        overlays: &HashMap<PathBuf, String>,
+        sources: &mut HashMap<PathBuf, String>,
+        problems: &mut Vec<String>,
+    ) -> io::Result<()> {
+        sources.extend(project.stage(overlays)?);
+        for dep in &project.dependencies {
+            if dep.is_contract {
+                self.stage_interface(dep, overlays, problems)?;
+            } else {
+                problems.extend(dep.project.unresolved.iter().cloned());
+                self.stage(&dep.project, overlays, sources, problems)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Contract dependencies are imported through an interface library generated from their
+    /// ABI, which needs the dependency to parse and type-check. That is only redone when the
+    /// dependency's sources have changed since the last build.
+    fn stage_interface(
+        &self,
+        dep: &ResolvedDependency,
+        overlays: &HashMap<PathBuf, String>,
+        problems: &mut Vec<String>,
+    ) -> io::Result<()> {
+        let fingerprint = dep.project.fingerprint(overlays)?;
+        let cached = self
+            .interfaces
+            .get(&dep.project.root)
+            .filter(|cached| cached.0 == fingerprint)
+            .map(|cached| cached.1.clone());
+        let interface = match cached {
+            Some(interface) => interface,
+            None => {
+                let analysis = self.parse(&dep.project, overlays);
+                let interface = analysis
+                    .ast
+                    .filter(|_| analysis.type_checked)
+                    .and_then(|contract| contract.abi(&Handler::default()).ok())
+                    .map(|abi| interface_source(&abi));
+                self.interfaces
+                    .insert(dep.project.root.clone(), (fingerprint, interface.clone()));
+                interface
+            }
+        };
+
+        let path = dep.interface_entry();
+        let Some(interface) = interface else {
+            problems.push(format!(
+                "contract dependency `{}` has errors, its interface is unavailable",
+                dep.name
+            ));
+            // Do not let an interface from an earlier, valid version linger.
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, interface)
+    }
+}
+
+
+impl Backend {
+    fn encoding(&self) -> Encoding {
+        self.encoding.get().copied().unwrap_or_default()
+    }
 
-        let path: PathBuf = path.to_string().into();
+    /// The semantic tokens of `uri` in `range`, or in the whole document, encoded relative to
+    /// each other.
+    fn semantic_tokens(&self, uri: &str, range: Option<Range>) -> Option<Vec<SemanticToken>> {
+        let im_complete_tokens = self.semantic_token_map.get(uri)?;
+        let rope = self.document_map.get(uri)?;
+        let encoding = self.encoding();
+        let bounds = match range {
+            Some(range) => {
+                encoding.clamped_offset(&rope, range.start)..encoding.clamped_offset(&rope, range.end)
+            }
+            None => 0..rope.len_bytes(),
+        };
+        let mut pre_line = 0;
+        let mut pre_start = 0;
+        let semantic_tokens = im_complete_tokens
+            .iter()
+            .filter(|token| bounds.contains(&token.start))
+            .filter_map(|token| {
+                let Position { line, character: start } = encoding.position(&rope, token.start)?;
+                let text = rope.get_byte_slice(token.start..token.start + token.length)?;
+                let ret = Some(SemanticToken {
+                    delta_line: line - pre_line,
+                    delta_start: if line == pre_line {
+                        start - pre_start
+                    } else {
+                        start
+                    },
+                    length: encoding.len(text) as u32,
+                    token_type: token.token_type as u32,
+                    token_modifiers_bitset: token.modifiers,
+                });
+                pre_line = line;
+                pre_start = start;
+                ret
+            })
+            .collect::<Vec<_>>();
+        Some(semantic_tokens)
+    }
 
-        let empty_map: HashMap<&str, &Path, FxBuildHasher> = HashMap::with_hasher(FxBuildHasher::default());
+    /// Stop applying changes to `uri`, whose buffer no longer matches the client's because of
+    /// `reason`, and tell the user the first time.
+    async fn lose_sync(&self, uri: &str, reason: String) {
+        self.client
+            .log_message(
+                MessageType::ERROR,
+                format!("failed to apply change to {uri}: {reason}"),
+            )
+            .await;
+        if self.desynced.insert(uri.to_string()) {
+            self.client
+                .show_message(
+                    MessageType::ERROR,
+                    format!(
+                        "The Pint language server lost track of the changes to {uri} ({reason}). \
+                         Close and reopen it to bring it back in sync."
+                    ),
+                )
+                .await;
+        }
+    }
 
-        let ast = pintc::parser::parse_project(&handler, &empty_map, &path);
-        
+    /// Keep `data` as the semantic tokens last sent for `uri`, returning its new `result_id`.
+    fn remember_semantic_tokens(&self, uri: &str, data: &[SemanticToken]) -> String {
+        let result_id = self.next_result_id.fetch_add(1, Ordering::Relaxed).to_string();
+        self.semantic_result_map
+            .insert(uri.to_string(), (result_id.clone(), data.to_vec()));
+        result_id
+    }
 
-        let (parse_errors, _parse_warnings) = handler.consume();
+    /// The workspace folders the client has told about.
+    fn workspace_folders(&self) -> Vec<Url> {
+        self.workspace_map
+            .iter()
+            .filter_map(|folder| Url::parse(folder.key()).ok())
+            .collect()
+    }
 
+    /// Find the packages in the workspace folder `folder`, unless it is removed in the meantime.
+    /// Walking a large folder takes a while, so it runs off the threads serving requests.
+    async fn index_folder(&self, folder: &Url) {
+        let Ok(dir) = folder.to_file_path() else {
+            return;
+        };
+        let scratch_dir = self.scratch_dir.clone();
+        let found = tokio::task::spawn_blocking(move || {
+            find_manifests(&dir)
+                .iter()
+                .filter_map(|manifest| Project::locate(manifest, &scratch_dir).ok())
+                .collect()
+        })
+        .await;
+        match found {
+            Ok(projects) => {
+                if let Some(mut indexed) = self.workspace_map.get_mut(folder.as_str()) {
+                    *indexed = projects;
+                }
+            }
+            Err(err) => {
+                self.client
+                    .log_message(MessageType::ERROR, format!("failed to index {folder}: {err}"))
+                    .await
+            }
+        }
+    }
 
-        let diagnostics = parse_errors
+    /// The workspace symbols declared in `file`, a source file of `project`.
+    #[allow(deprecated)]
+    fn file_symbols(&self, project: &Project, file: &Path) -> Vec<SymbolInformation> {
+        let Some((uri, rope, ast)) = self.source(file) else {
+            return vec![];
+        };
+        let package = project.manifest.as_ref().map(|manifest| manifest.package.name.clone());
+        let module = project.module_path(file).unwrap_or_default();
+        searchable_symbols(&ast)
             .into_iter()
-            .filter_map(|item| {
-                let trait_object: &dyn pintc::error::ReportableError = &item;
-                let (message, span) = (trait_object.display_raw(), trait_object.span());
-
-            || -> Option<Diagnostic> {
-                    let start_position = offset_to_position(span.start(), &rope)?;
-                    let end_position = offset_to_position(span.end(), &rope)?;
-                    Some(Diagnostic::new_simple(
-                        Range::new(start_position, end_position),
-                        message,
-                    ))
-                }()
+            .filter_map(|(symbol, container)| {
+                let range = self.encoding().range(&rope, &symbol.selection_span)?;
+                let container = package
+                    .iter()
+                    .chain(&module)
+                    .chain(&container)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                Some(SymbolInformation {
+                    name: symbol.name,
+                    kind: symbol.kind,
+                    tags: None,
+                    deprecated: None,
+                    location: Location::new(uri.clone(), range),
+                    container_name: (!container.is_empty()).then(|| container.join("::")),
+                })
             })
+            .collect()
+    }
+
+    /// The packages in the workspace folders and every package they depend on, each once.
+    fn workspace_projects(&self) -> Vec<Project> {
+        let mut projects: Vec<Project> = vec![];
+        let mut pending = self
+            .workspace_map
+            .iter()
+            .flat_map(|folder| folder.value().clone())
             .collect::<Vec<_>>();
-        
-        if ast.is_ok() {
-            backend.ast_map.insert(params.uri.to_string(), ast.unwrap());
+        while let Some(project) = pending.pop() {
+            if projects.iter().any(|seen| seen.root == project.root) {
+                continue;
+            }
+            pending.extend(project.dependencies.iter().map(|dep| dep.project.clone()));
+            projects.push(project);
         }
+        projects
+    }
 
-        diagnostics
+    /// The project `uri` belongs to, falling back to analysing it alone (with the reason) when
+    /// its manifest cannot be read.
+    fn project_for(&self, uri: &Url) -> (Project, Option<String>) {
+        let path = uri
+            .to_file_path()
+            .unwrap_or_else(|_| PathBuf::from(uri.path()));
+        match Project::locate(&path, &self.scratch_dir) {
+            Ok(project) => (project, None),
+            Err(err) => (Project::loose(&path, &self.scratch_dir), Some(err)),
+        }
+    }
+
+    /// The text and syntax tree of `file`, from its open document or else from disk.
+    fn source(&self, file: &Path) -> Option<(Url, Rope, Ast)> {
+        let uri = Url::from_file_path(file).ok()?;
+        if let Some(rope) = self.document_map.get(&uri.to_string()) {
+            let ast = self.syntax_map.get(&uri.to_string())?.clone();
+            return Some((uri, rope.clone(), ast));
+        }
+        let rope = Rope::from_str(&fs::read_to_string(file).ok()?);
+        let ast = parse(&rope.to_string()).ast?;
+        Some((uri, rope, ast))
     }
-}
 
+    /// The path from the root module of `project` of what the name at `offset` in `uri` refers
+    /// to, for names declared in other modules. pintc knows best, but types and macros are gone
+    /// from the contract, so those are resolved through `use`s instead.
+    fn item_path(&self, uri: &Url, project: &Project, offset: usize) -> Option<Vec<String>> {
+        let ast = self.syntax_map.get(&uri.to_string())?;
+        let file = uri.to_file_path().ok()?;
+        let module = project.module_path(&file)?;
+        if let Some(target) = use_path_at(&ast, &module, offset) {
+            return Some(target);
+        }
+        let (path, at) = path_at(&ast, offset)?;
+        // A contract built before the latest edits may resolve the name to something since
+        // renamed or removed, so it is only asked while the document matches it.
+        let version = self.version_map.get(&uri.to_string()).map(|v| *v);
+        let resolved = self.ast_map.get(&uri.to_string()).and_then(|snapshot| {
+            if version.is_some_and(|version| snapshot.is_stale(version)) {
+                return None;
+            }
+            let staged = project.staged_path(&file)?;
+            let offset = snapshot.edits.to_snapshot(offset);
+            contract_path(&snapshot.contract, &staged, offset)
+        });
+        let target = match resolved {
+            Some(resolved) => {
+                let mut target = resolved
+                    .trim_start_matches("::")
+                    .split("::")
+                    .map(String::from)
+                    .collect::<Vec<_>>();
+                target.truncate(target.len().saturating_sub(path.segments.len() - 1 - at));
+                target
+            }
+            None => {
+                let imports = imports(&ast, &module);
+                let names = path.segments[..=at]
+                    .iter()
+                    .map(|segment| segment.name.clone())
+                    .collect::<Vec<_>>();
+                absolute_path(path.absolute, &names, &module, &imports)
+            }
+        };
+        Some(target)
+    }
 
-impl Backend {
-    async fn on_change(&self, params: TextDocumentItem) {
-        let rope = ropey::Rope::from_str(&params.text);
+    /// The completions after `segments` and `::` in `uri`: the fields after `storage::`, the
+    /// items and submodules of a module, or the variants of a union.
+    fn path_completion(
+        &self,
+        uri: &Url,
+        project: &Project,
+        absolute: bool,
+        segments: &[String],
+    ) -> Option<Vec<ImCompleteCompletionItem>> {
+        if !absolute && segments == ["storage"] {
+            let (_, _, ast) = self.source(&project.root.join(&project.entry))?;
+            return Some(storage_fields(&ast));
+        }
+        let file = uri.to_file_path().ok()?;
+        let module = project.module_path(&file)?;
+        let imports = imports(&*self.syntax_map.get(&uri.to_string())?, &module);
+        let target = absolute_path(absolute, segments, &module, &imports);
+        if let Some(file) = project.module_file(&target) {
+            let (_, _, ast) = self.source(&file)?;
+            return Some(module_items(&ast, project.submodules(&target)));
+        }
+        let (union, module) = target.split_last()?;
+        let (_, _, ast) = self.source(&project.module_file(module)?)?;
+        Some(union_variants(&ast, union))
+    }
+
+    /// Where the item at `path`, a path from the root module of `project`, is declared. The
+    /// path is split into a module and a name at each point in turn, as union variants take
+    /// two segments. A path naming a module leads to the start of its file.
+    fn declaration(&self, project: &Project, path: &[String]) -> Option<Location> {
+        if let Some(file) = project.module_file(path).filter(|_| !path.is_empty()) {
+            let uri = Url::from_file_path(file).ok()?;
+            return Some(Location::new(uri, Range::default()));
+        }
+        let (uri, rope, _, span) = self.item_source(project, path)?;
+        let range = self.encoding().range(&rope, &span)?;
+        Some(Location::new(uri, range))
+    }
+
+    /// The source of the module declaring the item at `path`, and the span of its name there.
+    fn item_source(&self, project: &Project, path: &[String]) -> Option<(Url, Rope, Ast, Span)> {
+        (0..path.len()).rev().find_map(|split| {
+            let file = project.module_file(&path[..split])?;
+            let (uri, rope, ast) = self.source(&file)?;
+            let (_, span) = find_declaration(&ast, &path[split..].join("::"))?;
+            Some((uri, rope, ast, span))
+        })
+    }
+
+    /// The details and, for predicates, types, macros and such, the signature of the
+    /// declaration of the name at `offset` in `uri`.
+    fn hover_declaration(
+        &self,
+        uri: &Url,
+        project: &Project,
+        offset: usize,
+    ) -> Option<(Details, Option<String>)> {
+        let rope = self.document_map.get(&uri.to_string())?.clone();
+        let ast = self.syntax_map.get(&uri.to_string())?.clone();
+        let (rope, ast, span) = if let Some(field) = storage_field_at(&ast, offset) {
+            let (_, rope, ast) = self.source(&project.root.join(&project.entry))?;
+            let span = storage_declaration(&ast, &field)?;
+            (rope, ast, span)
+        } else if let Some((_, span)) = get_definition(&ast, offset) {
+            (rope, ast, span)
+        } else {
+            let path = self.item_path(uri, project, offset)?;
+            let (_, rope, ast, span) = self.item_source(project, &path)?;
+            (rope, ast, span)
+        };
+        let signature = signature(&ast, &span);
+        Some((self.details(rope, ast, span)?, signature))
+    }
+
+    /// The signature with its inferred type of the `let` without a type annotation the name at
+    /// `offset` in `uri` refers to.
+    fn inferred_let(&self, uri: &Url, project: &Project, offset: usize) -> Option<String> {
+        let ast = self.syntax_map.get(&uri.to_string())?.clone();
+        let (_, span) = get_definition(&ast, offset)?;
+        let snapshot = self.ast_map.get(&uri.to_string())?;
+        let staged = project.staged_path(&uri.to_file_path().ok()?)?;
+        let_signature(&ast, &span, &snapshot, &staged)
+    }
+
+    #[allow(deprecated)]
+    fn document_symbol_from(
+        &self,
+        rope: &Rope,
+        symbol: ImCompleteDocumentSymbol,
+    ) -> Option<DocumentSymbol> {
+        let children = symbol
+            .children
+            .into_iter()
+            .filter_map(|child| self.document_symbol_from(rope, child))
+            .collect::<Vec<_>>();
+        Some(DocumentSymbol {
+            name: symbol.name,
+            detail: symbol.detail,
+            kind: symbol.kind,
+            tags: None,
+            deprecated: None,
+            range: self.encoding().range(rope, &symbol.span)?,
+            selection_range: self.encoding().range(rope, &symbol.selection_span)?,
+            children: (!children.is_empty()).then_some(children),
+        })
+    }
+
+    /// The details of what a completion item names, for completionItem/resolve.
+    fn completion_details(&self, data: CompletionData) -> Option<Details> {
+        let (rope, ast, span) = match data {
+            CompletionData::Local { uri, offset, name } => {
+                let rope = self.document_map.get(&uri.to_string())?.clone();
+                let ast = self.syntax_map.get(&uri.to_string())?.clone();
+                let span = local_declaration(&ast, offset, &name)?;
+                (rope, ast, span)
+            }
+            CompletionData::Path {
+                uri,
+                absolute,
+                segments,
+            } => {
+                let project = self.project_map.get(&uri.to_string())?.clone();
+                if let [storage, field] = segments.as_slice() {
+                    if !absolute && storage == "storage" {
+                        let (_, rope, ast) = self.source(&project.root.join(&project.entry))?;
+                        let span = storage_declaration(&ast, field)?;
+                        return self.details(rope, ast, span);
+                    }
+                }
+                let module = project.module_path(&uri.to_file_path().ok()?)?;
+                let imports = imports(&*self.syntax_map.get(&uri.to_string())?, &module);
+                let path = absolute_path(absolute, &segments, &module, &imports);
+                let (_, rope, ast, span) = self.item_source(&project, &path)?;
+                (rope, ast, span)
+            }
+        };
+        self.details(rope, ast, span)
+    }
+
+    fn details(&self, rope: Rope, ast: Ast, span: Span) -> Option<Details> {
+        let text = rope.to_string();
+        // Comments are not kept around, so they are lexed again here, for the one item.
+        let comments = parse(&text).comments;
+        declaration_details(&ast, &comments, &text, &span)
+    }
+
+    /// What a rename at `position` in `uri` would apply to, and the range of the name there,
+    /// or why nothing can be renamed there.
+    fn rename_target(
+        &self,
+        uri: &Url,
+        position: Position,
+    ) -> std::result::Result<(Symbol, Range), String> {
+        let nothing = || "there is nothing to rename here".to_string();
+        let encoding = self.encoding();
+        let rope = self.document_map.get(&uri.to_string()).ok_or_else(nothing)?.clone();
+        let offset = encoding.offset(&rope, position).ok_or_else(nothing)?;
+        let word = word_at(&rope.to_string(), offset).ok_or_else(nothing)?;
+        let name = rope.byte_slice(word.clone()).to_string();
+        check_name(&name).map_err(|reason| format!("{reason} and cannot be renamed"))?;
+
+        let project = self
+            .project_map
+            .get(&uri.to_string())
+            .ok_or_else(nothing)?
+            .clone();
+        let file = uri.to_file_path().map_err(|_| nothing())?;
+        let module = project.module_path(&file).ok_or_else(nothing)?;
+        let symbol = self
+            .syntax_map
+            .get(&uri.to_string())
+            .and_then(|ast| symbol_at(&ast, &module, offset));
+        let symbol = match symbol {
+            Some(symbol) => symbol,
+            None => {
+                let item = self.item_path(uri, &project, offset).ok_or_else(nothing)?;
+                if project.module_file(&item).is_some() {
+                    return Err(format!("module `{name}` cannot be renamed"));
+                }
+                Symbol::Item(item)
+            }
+        };
+        if let Symbol::Item(item) = &symbol {
+            let declared_here = self
+                .declaration(&project, item)
+                .and_then(|location| location.uri.to_file_path().ok())
+                .is_some_and(|file| project.contains(&file));
+            if !declared_here {
+                return Err(format!("`{name}` is not declared in this package"));
+            }
+        }
+        let range = encoding.range(&rope, &word).ok_or_else(nothing)?;
+        Ok((symbol, range))
+    }
+
+    /// Every access to the storage field `field` in the project of `uri`. Storage can only be
+    /// declared and accessed in the entry module, so that is the only file searched. The
+    /// contract is used while it matches the text, the syntax tree once the text has moved on.
+    fn storage_references(
+        &self,
+        uri: &Url,
+        field: &str,
+        include_declaration: bool,
+    ) -> Option<Vec<Location>> {
+        let project = self.project_map.get(&uri.to_string())?.clone();
+        let entry = project.root.join(&project.entry);
+        let (entry_uri, rope, ast) = self.source(&entry)?;
+        let snapshot = match self.version_map.get(&entry_uri.to_string()).map(|v| *v) {
+            Some(version) => self
+                .ast_map
+                .get(&entry_uri.to_string())
+                .filter(|snapshot| !snapshot.is_stale(version))
+                .map(|snapshot| snapshot.contract.clone()),
+            // Unopened files are staged as they are on disk, so any contract of the project
+            // matches them.
+            None => self
+                .ast_map
+                .get(&uri.to_string())
+                .map(|snapshot| snapshot.contract.clone()),
+        };
+
+        let mut spans = match snapshot {
+            Some(contract) => {
+                let staged_entry = project.staged_entry();
+                storage_accesses(&contract, field)
+                    .into_iter()
+                    .filter(|(file, _)| *file == staged_entry)
+                    .map(|(_, span)| span)
+                    .collect()
+            }
+            None => storage_references(&ast, field),
+        };
+        if include_declaration {
+            spans.extend(storage_declaration(&ast, field));
+        }
+        spans.sort_by_key(|span| span.start);
+        let encoding = self.encoding();
+        let locations = spans
+            .iter()
+            .filter_map(|span| Some(Location::new(entry_uri.clone(), encoding.range(&rope, span)?)))
+            .collect();
+        Some(locations)
+    }
+
+    /// The text of every open document, keyed by path. Besides the project's own files these
+    /// may belong to its dependencies.
+    fn overlays(&self) -> HashMap<PathBuf, String> {
         self.document_map
-            .insert(params.uri.to_string(), rope.clone());
+            .iter()
+            .filter_map(|entry| {
+                let path = Url::parse(entry.key()).ok()?.to_file_path().ok()?;
+                Some((path, entry.value().to_string()))
+            })
+            .collect()
+    }
+
+    async fn on_change(&self, params: TextDocumentItem) {
+        // The syntax tree is cheap to get and is what completion and highlighting work from, so
+        // it is updated before anything waits.
+        let text = self
+            .document_map
+            .get(&params.uri.to_string())
+            .map(|rope| rope.to_string())
+            .unwrap_or_default();
+        let syntax = parse(&text);
+        if let Ok(path) = params.uri.to_file_path() {
+            self.symbol_map.remove(&path);
+        }
+        let mut semantic_tokens = syntax.semantic_tokens;
+        if let Some(ast) = syntax.ast {
+            semantic_tokens.extend(semantic_token_from_ast(&ast));
+            self.syntax_map.insert(params.uri.to_string(), ast);
+        }
+        semantic_tokens.sort_by_key(|token| token.start);
+        // The lexer's tokens come first, so their types win over a name the syntax tree also
+        // covers, while the modifiers of both are kept.
+        semantic_tokens.dedup_by(|later, earlier| {
+            let duplicate = later.start == earlier.start;
+            if duplicate {
+                earlier.modifiers |= later.modifiers;
+            }
+            duplicate
+        });
+        self.semantic_token_map
+            .insert(params.uri.to_string(), semantic_tokens);
+
+        let (project, manifest_error) = self.project_for(&params.uri);
+        if let Some(err) = manifest_error {
+            self.client
+                .log_message(
+                    MessageType::WARNING,
+                    format!("ignoring invalid manifest: {err}"),
+                )
+                .await;
+        }
 
-        let non_send_var = Arc::new(Mutex::new(NonSend::new()));
-        //let non_send_var = Arc::clone(&non_send_var);
+        // While typing, only the build after the last keystroke is worth running.
+        let generation = {
+            let mut generation = self.build_generation.entry(project.root.clone()).or_default();
+            *generation += 1;
+            *generation
+        };
+        tokio::time::sleep(BUILD_DELAY).await;
+        let overtaken = || {
+            self.build_generation
+                .get(&project.root)
+                .is_some_and(|latest| *latest != generation)
+        };
+        if overtaken() {
+            return;
+        }
+
+        // The versions the build is of, taken together with the text so they match.
+        let overlays = self.overlays();
+        let versions: HashMap<PathBuf, i32> = overlays
+            .keys()
+            .filter_map(|path| {
+                let uri = Url::from_file_path(path).ok()?;
+                Some((path.clone(), *self.version_map.get(&uri.to_string())?))
+            })
+            .collect();
+        let version = self
+            .version_map
+            .get(&params.uri.to_string())
+            .map_or(params.version, |v| *v);
 
-        let data = non_send_var.lock().await;
+        // pintc blocks for as long as it takes, so it runs off the threads serving requests.
+        let non_send = NonSend::new(self.encoding(), self.interface_map.clone());
+        let built = {
+            let project = project.clone();
+            tokio::task::spawn_blocking(move || non_send.parse(&project, &overlays)).await
+        };
+        let analysis = match built {
+            Ok(analysis) => analysis,
+            Err(err) => {
+                self.client
+                    .log_message(MessageType::ERROR, format!("build failed: {err}"))
+                    .await;
+                return;
+            }
+        };
+        if overtaken() {
+            return;
+        }
 
-        let diagnostics = data.parse(&params, &self);
-        
-        self.client.log_message(MessageType::INFO, diagnostics.len());
+        // Every open document of the project shares the analysis of the whole project. When
+        // there is no contract, the documents keep their last snapshot. Documents edited while
+        // pintc ran keep theirs too, as the edits since would be missing from the new one.
+        let current = |uri: &Url, version: Option<i32>| {
+            version.is_some() && self.version_map.get(&uri.to_string()).map(|v| *v) == version
+        };
+        for path in versions.keys().filter(|path| project.contains(path)) {
+            let Ok(uri) = Url::from_file_path(path) else {
+                continue;
+            };
+            if uri.path() == params.uri.path() {
+                continue;
+            }
+            if let Some(ast) = &analysis.ast {
+                let version = versions.get(path).copied();
+                if current(&uri, version) {
+                    self.ast_map
+                        .insert(uri.to_string(), Snapshot::new(ast.clone(), version.unwrap_or(0)));
+                }
+            }
+            self.project_map.insert(uri.to_string(), project.clone());
+        }
+        self.project_map
+            .insert(params.uri.to_string(), project.clone());
+        if let Some(ast) = analysis.ast {
+            if current(&params.uri, Some(version)) {
+                self.ast_map
+                    .insert(params.uri.to_string(), Snapshot::new(ast, version));
+            }
+        }
 
         self.client
-            .publish_diagnostics(params.uri.clone(), diagnostics, Some(params.version))
+            .log_message(
+                MessageType::INFO,
+                analysis.diagnostics.values().map(Vec::len).sum::<usize>(),
+            )
             .await;
 
-        // self.client
-        //     .log_message(MessageType::INFO, &format!("{:?}", semantic_tokens))
-        //     .await;
-        //self.semantic_token_map
-        //    .insert(params.uri.to_string(), semantic_tokens);
+        // Publish for every file of the project, including those without errors, so fixed files
+        // are cleared.
+        for (path, diagnostics) in analysis.diagnostics {
+            let (uri, version) = match Url::from_file_path(&path) {
+                Ok(uri) if uri.path() != params.uri.path() => {
+                    let version = versions.get(&path).copied();
+                    (uri, version)
+                }
+                _ => (params.uri.clone(), Some(version)),
+            };
+            self.client
+                .publish_diagnostics(uri, diagnostics, version)
+                .await;
+        }
     }
 }
 
@@ -452,8 +1897,20 @@ async fn main() {
     let (service, socket) = LspService::build(|client| Backend {
         client,
         ast_map: DashMap::new(),
+        syntax_map: DashMap::new(),
         document_map: DashMap::new(),
+        version_map: DashMap::new(),
         semantic_token_map: DashMap::new(),
+        semantic_result_map: DashMap::new(),
+        next_result_id: AtomicU64::new(0),
+        project_map: DashMap::new(),
+        scratch_dir: std::env::temp_dir().join(format!("pint-lsp-{}", std::process::id())),
+        encoding: OnceLock::new(),
+        workspace_map: DashMap::new(),
+        symbol_map: DashMap::new(),
+        desynced: DashSet::new(),
+        build_generation: DashMap::new(),
+        interface_map: Arc::new(DashMap::new()),
     })
     .finish();
 
@@ -461,9 +1918,54 @@ async fn main() {
     Server::new(stdin, stdout, socket).serve(service).await;
 }
 
-fn offset_to_position(offset: usize, rope: &Rope) -> Option<Position> {
-    let line = rope.try_char_to_line(offset).ok()?;
-    let first_char_of_line = rope.try_line_to_char(line).ok()?;
-    let column = offset - first_char_of_line;
-    Some(Position::new(line as u32, column as u32))
+/// Apply a single `textDocument/didChange` content change to `rope` in place, returning what it
+/// did in byte offsets. A change without a range replaces the whole buffer.
+fn apply_change(
+    rope: &mut Rope,
+    change: &TextDocumentContentChangeEvent,
+    encoding: Encoding,
+) -> std::result::Result<Edit, String> {
+    let Some(range) = change.range else {
+        let old_len = rope.len_bytes();
+        *rope = Rope::from_str(&change.text);
+        return Ok(Edit {
+            start: 0,
+            old_len,
+            new_len: change.text.len(),
+        });
+    };
+    let start = encoding
+        .offset(rope, range.start)
+        .ok_or_else(|| format!("invalid start position {:?}", range.start))?;
+    let end = encoding
+        .offset(rope, range.end)
+        .ok_or_else(|| format!("invalid end position {:?}", range.end))?;
+    if start > end {
+        return Err(format!("inverted range {range:?}"));
+    }
+    let edit = Edit {
+        start,
+        old_len: end - start,
+        new_len: change.text.len(),
+    };
+    let (start, end) = (rope.byte_to_char(start), rope.byte_to_char(end));
+    rope.try_remove(start..end).map_err(|err| err.to_string())?;
+    rope.try_insert(start, &change.text)
+        .map_err(|err| err.to_string())?;
+    Ok(edit)
+}
+
+/// The range `span` covers in the staged text of `path`.
+fn source_range(
+    path: &Path,
+    span: &SourceSpan,
+    sources: &HashMap<PathBuf, String>,
+    ropes: &mut HashMap<PathBuf, Rope>,
+    encoding: Encoding,
+) -> Option<Range> {
+    let rope = match ropes.entry(path.to_path_buf()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(Rope::from_str(sources.get(path)?)),
+    };
+    encoding.range(rope, &(span.start..span.end))
 }
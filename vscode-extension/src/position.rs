@@ -0,0 +1,120 @@
+use ropey::{Rope, RopeSlice};
+use tower_lsp::lsp_types::{Position, PositionEncodingKind, Range};
+
+use crate::chumsky::Span;
+
+/// What `Position::character` counts, as agreed with the client in `initialize`. Everything
+/// inside the server works in byte offsets, like pintc's spans, and is converted at the edges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    Utf8,
+    /// The LSP default, used when the client does not say what it supports.
+    #[default]
+    Utf16,
+    Utf32,
+}
+
+impl Encoding {
+    /// Pick from the encodings the client supports. UTF-8 is preferred as it needs no
+    /// conversion, otherwise the client's first choice that is known here.
+    pub fn negotiate(supported: Option<&[PositionEncodingKind]>) -> Self {
+        let Some(supported) = supported else {
+            return Encoding::default();
+        };
+        if supported.contains(&PositionEncodingKind::UTF8) {
+            return Encoding::Utf8;
+        }
+        supported
+            .iter()
+            .find_map(|kind| match kind.as_str() {
+                "utf-16" => Some(Encoding::Utf16),
+                "utf-32" => Some(Encoding::Utf32),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn kind(self) -> PositionEncodingKind {
+        match self {
+            Encoding::Utf8 => PositionEncodingKind::UTF8,
+            Encoding::Utf16 => PositionEncodingKind::UTF16,
+            Encoding::Utf32 => PositionEncodingKind::UTF32,
+        }
+    }
+
+    /// The length of `text` in code units.
+    pub fn len(self, text: RopeSlice) -> usize {
+        match self {
+            Encoding::Utf8 => text.len_bytes(),
+            Encoding::Utf16 => text.len_utf16_cu(),
+            Encoding::Utf32 => text.len_chars(),
+        }
+    }
+
+    /// The byte offset `position` refers to, or `None` if it is not in `rope` or splits a
+    /// character. A character past the end of its line means the end of the line, before its
+    /// line break.
+    pub fn offset(self, rope: &Rope, position: Position) -> Option<usize> {
+        let line = position.line as usize;
+        let character = position.character as usize;
+        if line == rope.len_lines() {
+            // A position on the line after the last one is how clients address the end of the buffer.
+            return (character == 0).then(|| rope.len_bytes());
+        }
+        let text = without_line_break(rope.get_line(line)?);
+        let character = character.min(self.len(text));
+        let char_idx = match self {
+            Encoding::Utf8 => {
+                let char_idx = text.try_byte_to_char(character).ok()?;
+                (text.char_to_byte(char_idx) == character).then_some(char_idx)?
+            }
+            Encoding::Utf16 => {
+                let char_idx = text.try_utf16_cu_to_char(character).ok()?;
+                (text.char_to_utf16_cu(char_idx) == character).then_some(char_idx)?
+            }
+            Encoding::Utf32 => character,
+        };
+        Some(rope.line_to_byte(line) + text.char_to_byte(char_idx))
+    }
+
+    /// The byte offset `position` refers to, like [`Encoding::offset`], except that a position
+    /// past the end of its line or of `rope` is moved back into it. For the ends of ranges
+    /// clients ask about, which may reach past the text.
+    pub fn clamped_offset(self, rope: &Rope, position: Position) -> usize {
+        let line = position.line as usize;
+        if line >= rope.len_lines() {
+            return rope.len_bytes();
+        }
+        self.offset(rope, position)
+            .unwrap_or_else(|| rope.line_to_byte(line + 1))
+    }
+
+    /// The position of the byte `offset` in `rope`. An offset inside a character is taken to
+    /// mean the start of that character.
+    pub fn position(self, rope: &Rope, offset: usize) -> Option<Position> {
+        let line = rope.try_byte_to_line(offset).ok()?;
+        let line_start = rope.line_to_byte(line);
+        let start = rope.char_to_byte(rope.byte_to_char(offset));
+        let character = self.len(rope.byte_slice(line_start..start));
+        Some(Position::new(line as u32, character as u32))
+    }
+
+    pub fn range(self, rope: &Rope, span: &Span) -> Option<Range> {
+        Some(Range::new(
+            self.position(rope, span.start)?,
+            self.position(rope, span.end)?,
+        ))
+    }
+}
+
+/// `line` without the `\n` or `\r\n` it ends with.
+fn without_line_break(line: RopeSlice) -> RopeSlice {
+    let mut end = line.len_chars();
+    if end > 0 && line.char(end - 1) == '\n' {
+        end -= 1;
+        if end > 0 && line.char(end - 1) == '\r' {
+            end -= 1;
+        }
+    }
+    line.slice(..end)
+}
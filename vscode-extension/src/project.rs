@@ -0,0 +1,643 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use pint_abi_types::{ContractABI, TypeABI};
+use serde::Deserialize;
+
+pub const MANIFEST_FILE_NAME: &str = "pint.toml";
+
+/// The parts of `pint.toml` the language server cares about.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Manifest {
+    pub package: Package,
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, Dependency>,
+    #[serde(default, rename = "contract-dependencies")]
+    pub contract_dependencies: BTreeMap<String, Dependency>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Package {
+    pub name: String,
+    #[serde(default)]
+    pub kind: PackageKind,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageKind {
+    #[default]
+    Contract,
+    Library,
+}
+
+/// An entry of `[dependencies]` or `[contract-dependencies]`. Only `path` sources can be resolved
+/// without fetching anything, the others are reported as unresolved.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Dependency {
+    pub path: Option<PathBuf>,
+    pub git: Option<String>,
+    pub package: Option<String>,
+}
+
+/// A dependency whose package was found on disk.
+#[derive(Debug, Clone)]
+pub struct ResolvedDependency {
+    /// The name the depending package imports it under.
+    pub name: String,
+    /// Whether it came from `[contract-dependencies]`, in which case it is imported through a
+    /// generated interface library rather than its own sources.
+    pub is_contract: bool,
+    pub project: Project,
+}
+
+impl ResolvedDependency {
+    /// Where the interface library of a contract dependency is written.
+    pub fn interface_entry(&self) -> PathBuf {
+        let mut root = self.project.staged_root.clone().into_os_string();
+        root.push("-interface");
+        PathBuf::from(root).join("src").join("lib.pnt")
+    }
+
+    /// The module pintc should parse when the dependency is imported.
+    pub fn staged_entry(&self) -> PathBuf {
+        if self.is_contract {
+            self.interface_entry()
+        } else {
+            self.project.staged_entry()
+        }
+    }
+}
+
+impl Manifest {
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?;
+        toml::from_str(&text).map_err(|err| format!("{}: {err}", path.display()))
+    }
+
+    /// The module pintc starts parsing from, relative to the package root.
+    pub fn entry_point(&self) -> PathBuf {
+        let file = match self.package.kind {
+            PackageKind::Contract => "contract.pnt",
+            PackageKind::Library => "lib.pnt",
+        };
+        Path::new("src").join(file)
+    }
+}
+
+/// Walk up from `path` to the closest directory holding a `pint.toml`.
+pub fn find_manifest(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .skip(1)
+        .map(|dir| dir.join(MANIFEST_FILE_NAME))
+        .find(|manifest| manifest.is_file())
+}
+
+/// How many directories deep below a workspace folder packages are looked for.
+const MAX_MANIFEST_DEPTH: usize = 8;
+
+/// The `pint.toml` of every package under `dir`. Hidden directories, build output,
+/// `node_modules` and symbolic links are skipped.
+pub fn find_manifests(dir: &Path) -> Vec<PathBuf> {
+    let mut manifests = vec![];
+    collect_manifests(dir, 0, &mut manifests);
+    manifests
+}
+
+fn collect_manifests(dir: &Path, depth: usize, manifests: &mut Vec<PathBuf>) {
+    let manifest = dir.join(MANIFEST_FILE_NAME);
+    if manifest.is_file() {
+        manifests.push(manifest);
+    }
+    if depth == MAX_MANIFEST_DEPTH {
+        return;
+    }
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    // `file_type` does not follow symbolic links, which could lead out of the folder or in
+    // circles.
+    let mut dirs = entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|ty| ty.is_dir()))
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    !name.starts_with('.') && !matches!(name, "out" | "target" | "node_modules")
+                })
+        })
+        .collect::<Vec<_>>();
+    dirs.sort();
+    for dir in dirs {
+        collect_manifests(&dir, depth + 1, manifests);
+    }
+}
+
+/// A unit of analysis: either a whole package described by a `pint.toml`, or a loose `.pnt` file
+/// that does not belong to one.
+///
+/// pintc reads its sources from disk, so a project is analysed from a mirror of its sources under
+/// `staged_root` in which open buffers replace the saved files. Spans produced by pintc point
+/// into that mirror and are mapped back with [`Project::real_path`].
+#[derive(Debug, Clone)]
+pub struct Project {
+    /// Directory containing `pint.toml`, or the directory of a loose file.
+    pub root: PathBuf,
+    /// Mirror of `root` inside the server's scratch directory.
+    pub staged_root: PathBuf,
+    /// Module pintc starts from, relative to `root`.
+    pub entry: PathBuf,
+    pub manifest: Option<Manifest>,
+    pub dependencies: Vec<ResolvedDependency>,
+    /// Why each dependency that could not be resolved was skipped.
+    pub unresolved: Vec<String>,
+}
+
+impl Project {
+    /// The package `file` belongs to, or a loose project for `file` alone when there is no
+    /// `pint.toml` above it.
+    pub fn locate(file: &Path, scratch_dir: &Path) -> Result<Self, String> {
+        let Some(manifest_path) = find_manifest(file) else {
+            return Ok(Self::loose(file, scratch_dir));
+        };
+        Self::package(&manifest_path, scratch_dir, &mut vec![])
+    }
+
+    /// The package described by `manifest_path`, with its dependencies resolved. `parents` holds
+    /// the roots of the packages that (transitively) depend on it, to catch cycles.
+    fn package(
+        manifest_path: &Path,
+        scratch_dir: &Path,
+        parents: &mut Vec<PathBuf>,
+    ) -> Result<Self, String> {
+        let manifest = Manifest::from_file(manifest_path)?;
+        let root = manifest_path
+            .parent()
+            .expect("manifest path has a parent")
+            .to_path_buf();
+
+        parents.push(root.clone());
+        let mut dependencies = vec![];
+        let mut unresolved = vec![];
+        let declared = manifest
+            .dependencies
+            .iter()
+            .map(|dep| (dep, false))
+            .chain(manifest.contract_dependencies.iter().map(|dep| (dep, true)));
+        for ((name, dep), is_contract) in declared {
+            match resolve_dependency(&root, dep, scratch_dir, parents) {
+                Ok(project) => dependencies.push(ResolvedDependency {
+                    name: name.clone(),
+                    is_contract,
+                    project,
+                }),
+                Err(err) => unresolved.push(format!(
+                    "dependency `{name}` of `{}`: {err}",
+                    manifest.package.name
+                )),
+            }
+        }
+        parents.pop();
+
+        Ok(Project {
+            staged_root: scratch_dir.join(staging_key(&root)),
+            entry: manifest.entry_point(),
+            manifest: Some(manifest),
+            root,
+            dependencies,
+            unresolved,
+        })
+    }
+
+    pub fn loose(file: &Path, scratch_dir: &Path) -> Self {
+        let root = file.parent().map_or_else(PathBuf::new, Path::to_path_buf);
+        Project {
+            staged_root: scratch_dir.join(staging_key(file)),
+            entry: file.file_name().map_or_else(PathBuf::new, PathBuf::from),
+            manifest: None,
+            root,
+            dependencies: vec![],
+            unresolved: vec![],
+        }
+    }
+
+    pub fn is_package(&self) -> bool {
+        self.manifest.is_some()
+    }
+
+    pub fn staged_entry(&self) -> PathBuf {
+        self.staged_root.join(&self.entry)
+    }
+
+    /// Map a path pintc reported back to the file the user edits. Files of library dependencies
+    /// map back too; generated contract interfaces have no real counterpart.
+    pub fn real_path(&self, staged: &Path) -> Option<PathBuf> {
+        if let Ok(relative) = staged.strip_prefix(&self.staged_root) {
+            return Some(self.root.join(relative));
+        }
+        self.libraries()
+            .find_map(|dep| dep.project.real_path(staged))
+    }
+
+    pub fn staged_path(&self, real: &Path) -> Option<PathBuf> {
+        real.strip_prefix(&self.root)
+            .ok()
+            .map(|relative| self.staged_root.join(relative))
+    }
+
+    pub fn contains(&self, file: &Path) -> bool {
+        if self.is_package() {
+            file.starts_with(self.root.join("src"))
+        } else {
+            file == self.root.join(&self.entry)
+        }
+    }
+
+    /// The library dependencies whose sources are parsed along with this project.
+    pub fn libraries(&self) -> impl Iterator<Item = &ResolvedDependency> {
+        self.dependencies.iter().filter(|dep| !dep.is_contract)
+    }
+
+    /// The dependency map handed to pintc: every dependency name reachable through library
+    /// dependencies, mapped to the staged module it resolves to. Names declared closer to this
+    /// project win.
+    pub fn dependency_entries(&self) -> Vec<(String, PathBuf)> {
+        let mut entries: Vec<(String, PathBuf)> = vec![];
+        let mut queue = vec![self];
+        while !queue.is_empty() {
+            let mut next = vec![];
+            for project in queue {
+                for dep in &project.dependencies {
+                    if !entries.iter().any(|(name, _)| *name == dep.name) {
+                        entries.push((dep.name.clone(), dep.staged_entry()));
+                    }
+                    if !dep.is_contract {
+                        next.push(&dep.project);
+                    }
+                }
+            }
+            queue = next;
+        }
+        entries
+    }
+
+    /// The real paths of every source file in the project.
+    pub fn source_files(&self) -> Vec<PathBuf> {
+        if !self.is_package() {
+            return vec![self.root.join(&self.entry)];
+        }
+        let mut files = vec![];
+        collect_sources(&self.root.join("src"), &mut files);
+        files.sort();
+        files
+    }
+
+    /// The module path of `file`, a real path, as it is written after `::` in Pint: `[]` for the
+    /// entry module, `["a", "b"]` for `a/b.pnt` or `a/b/b.pnt` next to it. Files of library
+    /// dependencies start with the name the dependency is imported under.
+    pub fn module_path(&self, file: &Path) -> Option<Vec<String>> {
+        if file == self.root.join(&self.entry) {
+            return Some(vec![]);
+        }
+        let dir = self.root.join(&self.entry);
+        let dir = dir.parent()?;
+        if let Ok(relative) = file.with_extension("").strip_prefix(dir) {
+            let mut module = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            // `a/b/b.pnt` is module `a::b`, like `a/b.pnt`.
+            if module.len() >= 2 && module[module.len() - 1] == module[module.len() - 2] {
+                module.pop();
+            }
+            return Some(module);
+        }
+        self.libraries().find_map(|dep| {
+            let mut module = dep.project.module_path(file)?;
+            module.insert(0, dep.name.clone());
+            Some(module)
+        })
+    }
+
+    /// The real path of the file holding `module`, the inverse of [`Project::module_path`].
+    pub fn module_file(&self, module: &[String]) -> Option<PathBuf> {
+        let Some((first, rest)) = module.split_first() else {
+            return Some(self.root.join(&self.entry));
+        };
+        let dir = self.root.join(&self.entry);
+        let dir = dir.parent()?;
+        let file = module
+            .iter()
+            .fold(dir.to_path_buf(), |path, segment| path.join(segment));
+        let nested = file.join(module.last().expect("module is not empty"));
+        let found = [file, nested]
+            .into_iter()
+            .map(|file| file.with_extension("pnt"))
+            .find(|file| file.is_file());
+        if found.is_some() {
+            return found;
+        }
+        self.libraries()
+            .find(|dep| dep.name == *first)
+            .and_then(|dep| dep.project.module_file(rest))
+    }
+
+    /// The names of the modules directly inside `module`. The root module also holds the
+    /// library dependencies.
+    pub fn submodules(&self, module: &[String]) -> Vec<String> {
+        let entry = self.root.join(&self.entry);
+        let Some(entry_dir) = entry.parent() else {
+            return vec![];
+        };
+        if let Some((first, rest)) = module.split_first() {
+            let local = self
+                .module_file(module)
+                .filter(|file| file.starts_with(entry_dir));
+            if local.is_none() {
+                return self
+                    .libraries()
+                    .find(|dep| dep.name == *first)
+                    .map_or_else(Vec::new, |dep| dep.project.submodules(rest));
+            }
+        }
+
+        let dir = module
+            .iter()
+            .fold(entry_dir.to_path_buf(), |dir, segment| dir.join(segment));
+        let mut names = vec![];
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            let Some(name) = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+            else {
+                continue;
+            };
+            let is_module = if path.is_dir() {
+                path.join(&name).with_extension("pnt").is_file()
+            } else {
+                // The entry and `a/b/b.pnt` are files of the module itself.
+                path.extension().is_some_and(|ext| ext == "pnt")
+                    && path != self.root.join(&self.entry)
+                    && module.last() != Some(&name)
+            };
+            if is_module && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        if module.is_empty() {
+            names.extend(self.libraries().map(|dep| dep.name.clone()));
+        }
+        names.sort();
+        names
+    }
+
+    /// Refresh the mirror under `staged_root`. `overlays` holds the text of open buffers keyed by
+    /// real path; every other source is copied from disk. Returns the text of each staged file,
+    /// keyed by real path.
+    pub fn stage(
+        &self,
+        overlays: &HashMap<PathBuf, String>,
+    ) -> io::Result<HashMap<PathBuf, String>> {
+        // Start from a clean mirror so files deleted or renamed on disk do not linger.
+        if self.staged_root.exists() {
+            fs::remove_dir_all(&self.staged_root)?;
+        }
+
+        let mut sources = HashMap::new();
+        for file in self.staged_files(overlays) {
+            let text = source_text(&file, overlays)?;
+            let staged = self
+                .staged_path(&file)
+                .expect("project sources live under the project root");
+            if let Some(parent) = staged.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&staged, &text)?;
+            sources.insert(file, text);
+        }
+        Ok(sources)
+    }
+
+    /// A hash of the text `stage` would mirror for this project and everything it depends on,
+    /// which stays the same for as long as what pintc makes of them does.
+    pub fn fingerprint(&self, overlays: &HashMap<PathBuf, String>) -> io::Result<u64> {
+        let mut hasher = DefaultHasher::new();
+        self.root.hash(&mut hasher);
+        self.unresolved.hash(&mut hasher);
+        for file in self.staged_files(overlays) {
+            file.hash(&mut hasher);
+            source_text(&file, overlays)?.hash(&mut hasher);
+        }
+        for dep in &self.dependencies {
+            dep.name.hash(&mut hasher);
+            dep.is_contract.hash(&mut hasher);
+            dep.project.fingerprint(overlays)?.hash(&mut hasher);
+        }
+        Ok(hasher.finish())
+    }
+
+    /// The sources on disk, plus open buffers of this project that are not saved yet.
+    fn staged_files(&self, overlays: &HashMap<PathBuf, String>) -> Vec<PathBuf> {
+        let mut files = self.source_files();
+        files.extend(
+            overlays
+                .keys()
+                .filter(|path| self.contains(path) && !files.contains(path))
+                .cloned()
+                .collect::<Vec<_>>(),
+        );
+        // Overlays come in no particular order, and fingerprints must not depend on it.
+        files.sort();
+        files
+    }
+}
+
+/// The text of `file`: its open buffer if there is one, or what is saved on disk.
+fn source_text(file: &Path, overlays: &HashMap<PathBuf, String>) -> io::Result<String> {
+    match overlays.get(file) {
+        Some(text) => Ok(text.clone()),
+        None => fs::read_to_string(file),
+    }
+}
+
+fn resolve_dependency(
+    root: &Path,
+    dep: &Dependency,
+    scratch_dir: &Path,
+    parents: &mut Vec<PathBuf>,
+) -> Result<Project, String> {
+    let Some(path) = &dep.path else {
+        return Err(match &dep.git {
+            Some(git) => format!("git source `{git}` is not supported, use a `path` dependency"),
+            None => "only `path` dependencies are supported".to_string(),
+        });
+    };
+    let dep_root = root.join(path);
+    let manifest_path = dep_root.join(MANIFEST_FILE_NAME);
+    if !manifest_path.is_file() {
+        return Err(format!("no {MANIFEST_FILE_NAME} in {}", dep_root.display()));
+    }
+    // Compare canonical paths so `../a` and `../b/../a` count as the same package.
+    let canonical = dep_root.canonicalize().unwrap_or(dep_root);
+    if parents.iter().any(|parent| {
+        parent
+            .canonicalize()
+            .is_ok_and(|parent| parent == canonical)
+    }) {
+        return Err("dependency cycle".to_string());
+    }
+    let project = Project::package(&canonical.join(MANIFEST_FILE_NAME), scratch_dir, parents)?;
+    let package_name = &project
+        .manifest
+        .as_ref()
+        .expect("packages have manifests")
+        .package
+        .name;
+    if let Some(expected) = &dep.package {
+        if expected != package_name {
+            return Err(format!(
+                "expected package `{expected}`, found `{package_name}`"
+            ));
+        }
+    }
+    Ok(project)
+}
+
+/// Render the library a contract dependency is imported as: the contract's `ADDRESS` and an
+/// `interface Contract` with its storage and predicates, mirroring what `pint build` generates
+/// from the compiled ABI. The address is a placeholder since the server never compiles to bytecode.
+pub fn interface_source(abi: &ContractABI) -> String {
+    let mut unions = BTreeMap::new();
+    for param in abi
+        .storage
+        .iter()
+        .chain(abi.predicates.iter().flat_map(|pred| &pred.params))
+    {
+        collect_unions(&param.ty, &mut unions);
+    }
+
+    let mut source = format!("const ADDRESS: b256 = 0x{};\n", "0".repeat(64));
+    for (name, variants) in &unions {
+        let variants = variants
+            .iter()
+            .map(|variant| match &variant.ty {
+                Some(ty) => format!("{}({})", last_segment(&variant.name), type_source(ty)),
+                None => last_segment(&variant.name).to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(" | ");
+        let _ = write!(source, "\nunion {name} = {variants};\n");
+    }
+
+    source.push_str("\ninterface Contract {\n");
+    if !abi.storage.is_empty() {
+        source.push_str("    storage {\n");
+        for var in &abi.storage {
+            let _ = writeln!(source, "        {}: {},", var.name, type_source(&var.ty));
+        }
+        source.push_str("    }\n");
+    }
+    for pred in &abi.predicates {
+        let params = pred
+            .params
+            .iter()
+            .map(|param| format!("{}: {}", last_segment(&param.name), type_source(&param.ty)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let _ = writeln!(
+            source,
+            "    predicate {}({params});",
+            last_segment(&pred.name)
+        );
+    }
+    source.push_str("}\n");
+    source
+}
+
+fn collect_unions(ty: &TypeABI, unions: &mut BTreeMap<String, Vec<pint_abi_types::UnionVariant>>) {
+    match ty {
+        TypeABI::Tuple(fields) => fields
+            .iter()
+            .for_each(|field| collect_unions(&field.ty, unions)),
+        TypeABI::Array { ty, .. } => collect_unions(ty, unions),
+        TypeABI::Map { ty_from, ty_to } => {
+            collect_unions(ty_from, unions);
+            collect_unions(ty_to, unions);
+        }
+        TypeABI::Union { name, variants } => {
+            for ty in variants.iter().filter_map(|variant| variant.ty.as_ref()) {
+                collect_unions(ty, unions);
+            }
+            unions
+                .entry(last_segment(name).to_string())
+                .or_insert_with(|| variants.clone());
+        }
+        TypeABI::Bool | TypeABI::Int | TypeABI::Real | TypeABI::String | TypeABI::B256 => {}
+    }
+}
+
+fn type_source(ty: &TypeABI) -> String {
+    match ty {
+        TypeABI::Bool => "bool".to_string(),
+        TypeABI::Int => "int".to_string(),
+        TypeABI::Real => "real".to_string(),
+        TypeABI::String => "string".to_string(),
+        TypeABI::B256 => "b256".to_string(),
+        TypeABI::Tuple(fields) => {
+            let fields = fields
+                .iter()
+                .map(|field| match &field.name {
+                    Some(name) => format!("{name}: {}", type_source(&field.ty)),
+                    None => type_source(&field.ty),
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("{{ {fields} }}")
+        }
+        TypeABI::Array { .. } => {
+            // `int[3][5]` is an array of 3 arrays of 5, so the outermost size is written first.
+            let mut sizes = String::new();
+            let mut element = ty;
+            while let TypeABI::Array { ty, size } = element {
+                let _ = write!(sizes, "[{size}]");
+                element = ty;
+            }
+            format!("{}{sizes}", type_source(element))
+        }
+        TypeABI::Union { name, .. } => last_segment(name).to_string(),
+        TypeABI::Map { ty_from, ty_to } => {
+            format!("( {} => {} )", type_source(ty_from), type_source(ty_to))
+        }
+    }
+}
+
+/// `::a::b` -> `b`. The ABI carries absolute paths, the generated library declares items at its
+/// top level.
+fn last_segment(path: &str) -> &str {
+    path.rsplit("::").next().unwrap_or(path)
+}
+
+fn collect_sources(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_sources(&path, files);
+        } else if path.extension().is_some_and(|ext| ext == "pnt") {
+            files.push(path);
+        }
+    }
+}
+
+fn staging_key(path: &Path) -> String {
+    format!("{:016x}", fxhash::hash64(path))
+}
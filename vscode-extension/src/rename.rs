@@ -0,0 +1,221 @@
+use crate::chumsky::{Ast, Decl, Span, Spanned, UseTree, KEYWORDS, PRIMITIVE_TYPES};
+use crate::jump_definition::{
+    absolute_path, contains, find_declaration, get_definition, imports, locals, name_paths,
+};
+use crate::reference::{storage_declaration, storage_field_at};
+
+/// What a rename applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Symbol {
+    /// A parameter, `let`, macro parameter or other name that only exists inside one
+    /// declaration, by where it is declared.
+    Local(Spanned<String>),
+    /// A field of the `storage` block.
+    Storage(String),
+    /// Something declared at the top level of a module, by its path from the root module, e.g.
+    /// `["lib", "Dir", "Up"]` for variant `Up` of union `Dir` in module `lib`.
+    Item(Vec<String>),
+}
+
+impl Symbol {
+    pub fn name(&self) -> &str {
+        match self {
+            Symbol::Local((name, _)) | Symbol::Storage(name) => name,
+            Symbol::Item(path) => path.last().map_or("", String::as_str),
+        }
+    }
+}
+
+/// The span of the word at `offset` in `text`, counting the `@` of macro names and the `$` of
+/// macro parameters as part of it.
+pub fn word_at(text: &str, offset: usize) -> Option<Span> {
+    let is_word = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '@' | '$');
+    let start = text.get(..offset)?.trim_end_matches(is_word).len();
+    let end = offset + text[offset..].len() - text[offset..].trim_start_matches(is_word).len();
+    (start < end).then_some(start..end)
+}
+
+/// Why `name` cannot be given to something, if it cannot.
+pub fn check_name(name: &str) -> Result<(), String> {
+    if PRIMITIVE_TYPES.contains(&name) {
+        return Err(format!("`{name}` is a built-in type"));
+    }
+    if KEYWORDS.contains(&name) {
+        return Err(format!("`{name}` is a keyword"));
+    }
+    if name.starts_with("__") {
+        return Err(format!("`{name}` is reserved for intrinsics"));
+    }
+    let ident = name.strip_prefix(['@', '$']).unwrap_or(name);
+    let mut chars = ident.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("`{name}` is not a valid identifier"));
+    }
+    Ok(())
+}
+
+/// `new_name` with the sigil of `old_name`, so macros can be renamed without typing the `@`.
+pub fn with_sigil(old_name: &str, new_name: &str) -> String {
+    match old_name.chars().next() {
+        Some(sigil @ ('@' | '$')) if !new_name.starts_with(sigil) => format!("{sigil}{new_name}"),
+        _ => new_name.to_string(),
+    }
+}
+
+/// The symbol named at `offset`, as far as `ast` alone can tell. `module` is the module of the
+/// document. Names declared in other modules are left to the caller, as are interface members,
+/// which belong to another contract.
+pub fn symbol_at(ast: &Ast, module: &[String], offset: usize) -> Option<Symbol> {
+    if let Some(field) = storage_field_at(ast, offset) {
+        return Some(Symbol::Storage(field));
+    }
+    let (name, span) = get_definition(ast, offset)?;
+    for decl in ast {
+        if let Decl::Union {
+            name: union,
+            variants,
+            ..
+        } = decl
+        {
+            if variants.iter().any(|variant| variant.name.span == span) {
+                let mut path = module.to_vec();
+                path.extend([union.name.clone(), name]);
+                return Some(Symbol::Item(path));
+            }
+        }
+    }
+    if find_declaration(ast, &name).is_some_and(|(_, global)| global == span) {
+        let mut path = module.to_vec();
+        path.push(name);
+        return Some(Symbol::Item(path));
+    }
+    let in_interface = ast
+        .iter()
+        .any(|decl| matches!(decl, Decl::Interface { .. }) && contains(&decl.span(), span.start));
+    (!in_interface).then_some(Symbol::Local((name, span)))
+}
+
+/// Whether the name at `offset` refers to a parameter or `let` rather than to a module item.
+fn is_local(ast: &Ast, offset: usize) -> bool {
+    get_definition(ast, offset).is_some_and(|(name, span)| {
+        find_declaration(ast, &name).is_none_or(|(_, global)| global != span)
+    })
+}
+
+/// The spans in `ast` that name `item`: its declaration if `module` declares it, the paths that
+/// resolve to it and the `use` items that import it. Paths through an alias of it are left
+/// alone, as the alias keeps its name.
+pub fn item_references(ast: &Ast, module: &[String], item: &[String]) -> Vec<Span> {
+    let Some((name, parent)) = item.split_last() else {
+        return vec![];
+    };
+    let mut spans = vec![];
+
+    // Union variants are declared one segment further from their module.
+    for split in [parent.len(), parent.len().saturating_sub(1)] {
+        if module == &item[..split] {
+            spans.extend(find_declaration(ast, &item[split..].join("::")).map(|(_, span)| span));
+        }
+    }
+
+    let imports = imports(ast, module);
+    for path in name_paths(ast) {
+        let names = path
+            .segments
+            .iter()
+            .map(|segment| segment.name.clone())
+            .collect::<Vec<_>>();
+        for (at, segment) in path.segments.iter().enumerate() {
+            if segment.name != *name
+                || absolute_path(path.absolute, &names[..=at], module, &imports) != item
+            {
+                continue;
+            }
+            if at == 0 && !path.absolute && is_local(ast, segment.span.start) {
+                continue;
+            }
+            spans.push(segment.span.clone());
+        }
+    }
+
+    fn use_references(
+        tree: &UseTree,
+        prefix: &mut Vec<String>,
+        item: &[String],
+        spans: &mut Vec<Span>,
+    ) {
+        match tree {
+            UseTree::Name(name) | UseTree::Alias { name, .. } => {
+                prefix.push(name.name.clone());
+                if prefix == item {
+                    spans.push(name.span.clone());
+                }
+                prefix.pop();
+            }
+            UseTree::Path {
+                prefix: head,
+                suffix,
+            } => {
+                prefix.push(head.name.clone());
+                if prefix == item {
+                    spans.push(head.span.clone());
+                }
+                use_references(suffix, prefix, item, spans);
+                prefix.pop();
+            }
+            UseTree::Group(trees) => {
+                for tree in trees {
+                    use_references(tree, prefix, item, spans);
+                }
+            }
+        }
+    }
+    for decl in ast {
+        if let Decl::Use { absolute, tree, .. } = decl {
+            let mut prefix = if *absolute { vec![] } else { module.to_vec() };
+            use_references(tree, &mut prefix, item, &mut spans);
+        }
+    }
+
+    spans.sort_by_key(|span| span.start);
+    spans.dedup();
+    spans
+}
+
+/// Whether giving `symbol` the name `new_name` would make it clash with, or be shadowed by,
+/// something already in `ast`. For an item, `ast` may be the module declaring it or one that
+/// refers to it; only modules importing it by name can clash.
+pub fn clashes(ast: &Ast, module: &[String], symbol: &Symbol, new_name: &str) -> bool {
+    let imports = imports(ast, module);
+    let taken = || {
+        imports.iter().any(|(name, _)| name == new_name)
+            || find_declaration(ast, new_name).is_some()
+    };
+    match symbol {
+        Symbol::Storage(_) => storage_declaration(ast, new_name).is_some(),
+        Symbol::Local((_, span)) => {
+            let in_scope = ast
+                .iter()
+                .find(|decl| contains(&decl.span(), span.start))
+                .is_some_and(|decl| locals(decl).iter().any(|local| local.name == new_name));
+            in_scope || taken()
+        }
+        Symbol::Item(item) => {
+            let (_, parent) = item.split_last().expect("items have a name");
+            if parent == module {
+                return taken();
+            }
+            // A variant only has to be unique within its union.
+            if let Some((union, union_module)) = parent.split_last() {
+                if union_module == module && find_declaration(ast, union).is_some() {
+                    return find_declaration(ast, &format!("{union}::{new_name}")).is_some();
+                }
+            }
+            imports.iter().any(|(_, path)| path == item) && taken()
+        }
+    }
+}
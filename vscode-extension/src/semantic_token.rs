@@ -1,100 +1,532 @@
 use std::collections::HashMap;
 
-use tower_lsp::lsp_types::{SemanticTokenType};
+use tower_lsp::lsp_types::{
+    SemanticToken, SemanticTokenModifier, SemanticTokenType, SemanticTokensEdit,
+};
 
-use crate::chumsky::{Expr, Func, ImCompleteSemanticToken, Spanned};
+use crate::chumsky::{Ast, Decl, Expr, ImCompleteSemanticToken, Path, Span, Type, UseTree};
+
+/// `0x` followed by 64 hex digits. The extension declares it as a kind of number, so themes
+/// without a colour for it fall back to theirs.
+pub const B256: SemanticTokenType = SemanticTokenType::new("b256");
 
 pub const LEGEND_TYPE: &[SemanticTokenType] = &[
-    SemanticTokenType::FUNCTION,
-    SemanticTokenType::VARIABLE,
-    SemanticTokenType::STRING,
+    SemanticTokenType::KEYWORD,
     SemanticTokenType::COMMENT,
+    SemanticTokenType::STRING,
     SemanticTokenType::NUMBER,
-    SemanticTokenType::KEYWORD,
+    B256,
     SemanticTokenType::OPERATOR,
+    SemanticTokenType::FUNCTION,
+    SemanticTokenType::MACRO,
     SemanticTokenType::PARAMETER,
+    SemanticTokenType::VARIABLE,
+    SemanticTokenType::PROPERTY,
+    SemanticTokenType::TYPE,
+    SemanticTokenType::ENUM,
+    SemanticTokenType::ENUM_MEMBER,
+    SemanticTokenType::INTERFACE,
+    SemanticTokenType::NAMESPACE,
 ];
 
-pub fn semantic_token_from_ast(ast: &HashMap<String, Func>) -> Vec<ImCompleteSemanticToken> {
-    let mut semantic_tokens = vec![];
-
-    ast.iter().for_each(|(_func_name, function)| {
-        function.args.iter().for_each(|(_, span)| {
-            semantic_tokens.push(ImCompleteSemanticToken {
-                start: span.start,
-                length: span.len(),
-                token_type: LEGEND_TYPE
-                    .iter()
-                    .position(|item| item == &SemanticTokenType::PARAMETER)
-                    .unwrap(),
-            });
-        });
-        let (_, span) = &function.name;
-        semantic_tokens.push(ImCompleteSemanticToken {
+/// A `mut storage::` access, or a `let` bound to one.
+pub const MUTABLE: SemanticTokenModifier = SemanticTokenModifier::new("mutable");
+/// The value of a name after the state change, `x'`.
+pub const NEXT_STATE: SemanticTokenModifier = SemanticTokenModifier::new("nextState");
+
+/// `readonly` marks a `storage::` access without `mut`, and a `let` bound to one.
+pub const LEGEND_MODIFIER: &[SemanticTokenModifier] = &[
+    SemanticTokenModifier::DECLARATION,
+    SemanticTokenModifier::READONLY,
+    MUTABLE,
+    NEXT_STATE,
+];
+
+/// The bit of `modifier` in a token's modifier set.
+pub fn modifier_bit(modifier: &SemanticTokenModifier) -> u32 {
+    let index = LEGEND_MODIFIER
+        .iter()
+        .position(|item| item == modifier)
+        .expect("token modifier is in the legend");
+    1 << index
+}
+
+/// The modifiers of a `let` initialised with `init`: whether it is bound to a storage access,
+/// and whether that access is `mut`.
+fn binding_modifiers(init: Option<&Expr>) -> u32 {
+    match init {
+        Some(Expr::Storage { mutable: true, .. }) => modifier_bit(&MUTABLE),
+        Some(Expr::Storage { mutable: false, .. }) => {
+            modifier_bit(&SemanticTokenModifier::READONLY)
+        }
+        _ => 0,
+    }
+}
+
+/// The index of `token_type` in [`LEGEND_TYPE`].
+pub fn legend_index(token_type: &SemanticTokenType) -> usize {
+    LEGEND_TYPE
+        .iter()
+        .position(|item| item == token_type)
+        .expect("token type is in the legend")
+}
+
+/// Whether the integer literal `literal` is a `b256`.
+pub fn is_b256(literal: &str) -> bool {
+    literal
+        .strip_prefix("0x")
+        .is_some_and(|digits| digits.len() == 64)
+}
+
+/// Tokens for the names in one module. Names are told apart by what declares them where that is
+/// in the module, and by their place in paths otherwise: modules are lowercase and unions are
+/// not, so the segment before a variant starts with a capital.
+struct Highlighter<'a> {
+    /// What each name declared at the top level of the module is.
+    items: HashMap<&'a str, SemanticTokenType>,
+    /// The parameters of the declaration being walked.
+    params: Vec<&'a str>,
+    /// The `let`s of the declaration being walked that are bound to storage, with the
+    /// modifiers their uses get.
+    bindings: HashMap<&'a str, u32>,
+    /// Where the names read after the state change, in `x'`, start.
+    next_state: Vec<usize>,
+    tokens: Vec<ImCompleteSemanticToken>,
+}
+
+impl<'a> Highlighter<'a> {
+    fn push(&mut self, span: &Span, token_type: SemanticTokenType) {
+        self.push_with(span, token_type, 0);
+    }
+
+    fn push_with(&mut self, span: &Span, token_type: SemanticTokenType, modifiers: u32) {
+        self.tokens.push(ImCompleteSemanticToken {
             start: span.start,
             length: span.len(),
-            token_type: LEGEND_TYPE
-                .iter()
-                .position(|item| item == &SemanticTokenType::FUNCTION)
-                .unwrap(),
+            token_type: legend_index(&token_type),
+            modifiers,
         });
-        semantic_token_from_expr(&function.body, &mut semantic_tokens);
-    });
+    }
 
-    semantic_tokens
-}
+    /// Push the name of a declaration.
+    fn declare(&mut self, span: &Span, token_type: SemanticTokenType) {
+        let declaration = modifier_bit(&SemanticTokenModifier::DECLARATION);
+        self.push_with(span, token_type, declaration);
+    }
 
-pub fn semantic_token_from_expr(
-    expr: &Spanned<Expr>,
-    semantic_tokens: &mut Vec<ImCompleteSemanticToken>,
-) {
-    match &expr.0 {
-        Expr::Error => {}
-        Expr::Value(_) => {}
-        Expr::List(_) => {}
-        Expr::Local((_name, span)) => {
-            semantic_tokens.push(ImCompleteSemanticToken {
-                start: span.start,
-                length: span.len(),
-                token_type: LEGEND_TYPE
-                    .iter()
-                    .position(|item| item == &SemanticTokenType::VARIABLE)
-                    .unwrap(),
-            });
+    fn is_union(&self, name: &str) -> bool {
+        match self.items.get(name) {
+            Some(token_type) => *token_type == SemanticTokenType::ENUM,
+            None => name.starts_with(|c: char| c.is_ascii_uppercase()),
         }
-        Expr::Let(_, rhs, rest, name_span) => {
-            semantic_tokens.push(ImCompleteSemanticToken {
-                start: name_span.start,
-                length: name_span.len(),
-                token_type: LEGEND_TYPE
-                    .iter()
-                    .position(|item| item == &SemanticTokenType::VARIABLE)
-                    .unwrap(),
-            });
-            semantic_token_from_expr(rhs, semantic_tokens);
-            semantic_token_from_expr(rest, semantic_tokens);
+    }
+
+    /// Highlight `path`, ending with a name of type `last`. The segments before it are modules,
+    /// or the union of a variant.
+    fn path(&mut self, path: &Path, last: SemanticTokenType) {
+        let Some((name, prefix)) = path.segments.split_last() else {
+            return;
+        };
+        for (index, segment) in prefix.iter().enumerate() {
+            let is_union = last == SemanticTokenType::ENUM_MEMBER && index + 1 == prefix.len();
+            let token_type = if is_union {
+                SemanticTokenType::ENUM
+            } else {
+                SemanticTokenType::NAMESPACE
+            };
+            self.push(&segment.span, token_type);
         }
-        Expr::Then(first, rest) => {
-            semantic_token_from_expr(first, semantic_tokens);
-            semantic_token_from_expr(rest, semantic_tokens);
+        // Macro names and parameters are highlighted by the lexer.
+        if !name.name.starts_with(['@', '$']) {
+            let modifiers = match path.segments.len() {
+                1 if !path.absolute => self.bindings.get(name.name.as_str()).copied(),
+                _ => None,
+            };
+            self.push_with(&name.span, last, modifiers.unwrap_or_default());
         }
-        Expr::Binary(lhs, _op, rhs) => {
-            semantic_token_from_expr(lhs, semantic_tokens);
-            semantic_token_from_expr(rhs, semantic_tokens);
+    }
+
+    /// What the last segment of `path` names, in an expression.
+    fn value_path(&mut self, path: &Path) {
+        let Some(name) = path.name() else {
+            return;
+        };
+        let token_type = match path.segments.len() {
+            1 if !path.absolute && self.params.contains(&name.name.as_str()) => {
+                SemanticTokenType::PARAMETER
+            }
+            len if len > 1 && self.is_union(&path.segments[len - 2].name) => {
+                SemanticTokenType::ENUM_MEMBER
+            }
+            _ => self
+                .items
+                .get(name.name.as_str())
+                .cloned()
+                .unwrap_or(SemanticTokenType::VARIABLE),
+        };
+        self.path(path, token_type);
+    }
+
+    fn ty(&mut self, ty: &Type) {
+        match ty {
+            // Primitive types are keywords to the lexer, which highlights them.
+            Type::Error(_) | Type::Primitive(..) => {}
+            Type::Custom(path) => {
+                let token_type = path
+                    .name()
+                    .and_then(|name| self.items.get(name.name.as_str()))
+                    .filter(|token_type| {
+                        **token_type == SemanticTokenType::ENUM
+                            || **token_type == SemanticTokenType::INTERFACE
+                    })
+                    .cloned()
+                    .unwrap_or(SemanticTokenType::TYPE);
+                self.path(path, token_type);
+            }
+            Type::Tuple(fields, _) => {
+                for (name, ty) in fields {
+                    if let Some(name) = name {
+                        self.push(&name.span, SemanticTokenType::PROPERTY);
+                    }
+                    self.ty(ty);
+                }
+            }
+            Type::Array { ty, size, .. } => {
+                self.ty(ty);
+                self.expr(size);
+            }
+            Type::Vector { ty, .. } => self.ty(ty),
+            Type::Map { from, to, .. } => {
+                self.ty(from);
+                self.ty(to);
+            }
         }
-        Expr::Call(expr, params) => {
-            semantic_token_from_expr(expr, semantic_tokens);
-            params.0.iter().for_each(|p| {
-                semantic_token_from_expr(p, semantic_tokens);
-            });
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        let mut types = vec![];
+        expr.walk(&mut |expr| match expr {
+            Expr::Path(path) => self.value_path(path),
+            Expr::MacroCall(call) => self.path(&call.name, SemanticTokenType::MACRO),
+            Expr::Storage {
+                name: Some(name), ..
+            } => {
+                let modifiers = binding_modifiers(Some(expr));
+                self.push_with(&name.span, SemanticTokenType::PROPERTY, modifiers);
+            }
+            Expr::NextState { expr, .. } => match &**expr {
+                Expr::Path(path) => self
+                    .next_state
+                    .extend(path.name().map(|name| name.span.start)),
+                Expr::Storage {
+                    name: Some(name), ..
+                } => self.next_state.push(name.span.start),
+                _ => {}
+            },
+            Expr::ExternalStorage {
+                interface, name, ..
+            } => {
+                self.path(interface, SemanticTokenType::INTERFACE);
+                if let Some(name) = name {
+                    self.push(&name.span, SemanticTokenType::PROPERTY);
+                }
+            }
+            Expr::Field {
+                field: Some(field), ..
+            } => self.push(&field.span, SemanticTokenType::PROPERTY),
+            Expr::PredicateCall {
+                path, predicate, ..
+            } => match predicate {
+                Some(predicate) => {
+                    self.path(path, SemanticTokenType::INTERFACE);
+                    self.push(&predicate.span, SemanticTokenType::FUNCTION);
+                }
+                None => self.path(path, SemanticTokenType::FUNCTION),
+            },
+            Expr::UnionVariant { path, .. } => self.path(path, SemanticTokenType::ENUM_MEMBER),
+            Expr::Tuple(fields, _) => {
+                for name in fields.iter().filter_map(|(name, _)| name.as_ref()) {
+                    self.push(&name.span, SemanticTokenType::PROPERTY);
+                }
+            }
+            Expr::Match { arms, .. } => {
+                for arm in arms {
+                    self.path(&arm.pattern, SemanticTokenType::ENUM_MEMBER);
+                    if let Some(binding) = &arm.binding {
+                        self.declare(&binding.span, SemanticTokenType::VARIABLE);
+                    }
+                }
+            }
+            Expr::Generator { ranges, .. } => {
+                for (index, _) in ranges {
+                    self.declare(&index.span, SemanticTokenType::VARIABLE);
+                }
+            }
+            Expr::Cast { ty, .. } => types.push(ty),
+            _ => {}
+        });
+        for ty in types {
+            self.ty(ty);
+        }
+    }
+
+    fn use_tree(&mut self, tree: &UseTree) {
+        match tree {
+            UseTree::Name(name) | UseTree::Alias { name, .. } => {
+                if let Some(token_type) = self.items.get(name.name.as_str()).cloned() {
+                    self.push(&name.span, token_type);
+                }
+            }
+            UseTree::Path { prefix, suffix } => {
+                self.push(&prefix.span, SemanticTokenType::NAMESPACE);
+                self.use_tree(suffix);
+            }
+            UseTree::Group(trees) => {
+                for tree in trees {
+                    self.use_tree(tree);
+                }
+            }
+        }
+    }
+
+    fn decl(&mut self, decl: &'a Decl) {
+        match decl {
+            Decl::Use { tree, .. } => self.use_tree(tree),
+            Decl::Predicate { name, params, .. } => {
+                self.declare(&name.span, SemanticTokenType::FUNCTION);
+                for param in params {
+                    self.declare(&param.name.span, SemanticTokenType::PARAMETER);
+                }
+            }
+            Decl::Let { name, init, .. } => {
+                let modifiers = modifier_bit(&SemanticTokenModifier::DECLARATION)
+                    | binding_modifiers(init.as_ref());
+                self.push_with(&name.span, SemanticTokenType::VARIABLE, modifiers);
+            }
+            Decl::Const { name, .. } => self.declare(&name.span, SemanticTokenType::VARIABLE),
+            Decl::NewType { name, .. } => self.declare(&name.span, SemanticTokenType::TYPE),
+            Decl::Union { name, variants, .. } => {
+                self.declare(&name.span, SemanticTokenType::ENUM);
+                for variant in variants {
+                    self.declare(&variant.name.span, SemanticTokenType::ENUM_MEMBER);
+                }
+            }
+            Decl::Storage { vars, .. } => {
+                for var in vars {
+                    self.declare(&var.name.span, SemanticTokenType::PROPERTY);
+                }
+            }
+            // The lexer gives these their types; the declaration is only known here.
+            Decl::Macro {
+                name, params, pack, ..
+            } => {
+                self.declare(&name.span, SemanticTokenType::MACRO);
+                for param in params.iter().chain(pack) {
+                    self.declare(&param.span, SemanticTokenType::PARAMETER);
+                }
+            }
+            Decl::Interface {
+                name,
+                storage,
+                predicates,
+                ..
+            } => {
+                self.declare(&name.span, SemanticTokenType::INTERFACE);
+                for var in storage {
+                    self.declare(&var.name.span, SemanticTokenType::PROPERTY);
+                }
+                for predicate in predicates {
+                    self.declare(&predicate.name.span, SemanticTokenType::FUNCTION);
+                    for param in &predicate.params {
+                        self.declare(&param.name.span, SemanticTokenType::PARAMETER);
+                    }
+                }
+            }
+            Decl::Match { arms, .. } => {
+                for arm in arms {
+                    self.path(&arm.pattern, SemanticTokenType::ENUM_MEMBER);
+                    if let Some(binding) = &arm.binding {
+                        self.declare(&binding.span, SemanticTokenType::VARIABLE);
+                    }
+                }
+            }
+            _ => {}
+        }
+        for ty in decl.types() {
+            self.ty(ty);
+        }
+        for expr in decl.exprs() {
+            self.expr(expr);
+        }
+    }
+}
+
+/// Tokens for the names in `ast`. Keywords, primitive types, literals, comments and macro names
+/// come from the lexer instead; where both have a token, the caller merges their modifiers.
+pub fn semantic_token_from_ast(ast: &Ast) -> Vec<ImCompleteSemanticToken> {
+    let mut items = HashMap::new();
+    for decl in ast {
+        let (name, token_type) = match decl {
+            Decl::Const { name, .. } => (name, SemanticTokenType::VARIABLE),
+            Decl::NewType { name, .. } => (name, SemanticTokenType::TYPE),
+            Decl::Union { name, .. } => (name, SemanticTokenType::ENUM),
+            Decl::Interface { name, .. } => (name, SemanticTokenType::INTERFACE),
+            Decl::Predicate { name, .. } => (name, SemanticTokenType::FUNCTION),
+            _ => continue,
+        };
+        items.insert(name.name.as_str(), token_type);
+    }
+    let mut highlighter = Highlighter {
+        items,
+        params: vec![],
+        bindings: HashMap::new(),
+        next_state: vec![],
+        tokens: vec![],
+    };
+
+    for decl in ast {
+        highlighter.params = match decl {
+            Decl::Predicate { params, .. } => params
+                .iter()
+                .map(|param| param.name.name.as_str())
+                .collect(),
+            _ => vec![],
+        };
+        highlighter.bindings.clear();
+        decl.walk(&mut |decl| {
+            if let Decl::Let {
+                name,
+                init: Some(init),
+                ..
+            } = decl
+            {
+                let modifiers = binding_modifiers(Some(init));
+                if modifiers != 0 {
+                    highlighter.bindings.insert(name.name.as_str(), modifiers);
+                }
+            }
+        });
+        decl.walk(&mut |decl| highlighter.decl(decl));
+    }
+
+    let next_state = modifier_bit(&NEXT_STATE);
+    for token in &mut highlighter.tokens {
+        if highlighter.next_state.contains(&token.start) {
+            token.modifiers |= next_state;
         }
-        Expr::If(test, consequent, alternative) => {
-            semantic_token_from_expr(test, semantic_tokens);
-            semantic_token_from_expr(consequent, semantic_tokens);
-            semantic_token_from_expr(alternative, semantic_tokens);
+    }
+    highlighter.tokens
+}
+
+/// The edit turning `old` into `new`: everything between the tokens they start and end with
+/// alike is replaced. Empty when nothing changed. Offsets count the five numbers each token is
+/// sent as.
+pub fn semantic_token_edits(
+    old: &[SemanticToken],
+    new: &[SemanticToken],
+) -> Vec<SemanticTokensEdit> {
+    let prefix = old
+        .iter()
+        .zip(new)
+        .take_while(|(old, new)| old == new)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old, new)| old == new)
+        .count();
+    let deleted = old.len() - prefix - suffix;
+    let inserted = &new[prefix..new.len() - suffix];
+    if deleted == 0 && inserted.is_empty() {
+        return vec![];
+    }
+    vec![SemanticTokensEdit {
+        start: 5 * prefix as u32,
+        delete_count: 5 * deleted as u32,
+        data: (!inserted.is_empty()).then(|| inserted.to_vec()),
+    }]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(delta_line: u32, delta_start: u32, length: u32) -> SemanticToken {
+        SemanticToken {
+            delta_line,
+            delta_start,
+            length,
+            token_type: 0,
+            token_modifiers_bitset: 0,
         }
-        Expr::Print(expr) => {
-            semantic_token_from_expr(expr, semantic_tokens);
+    }
+
+    /// The numbers `tokens` are sent as.
+    fn encode(tokens: &[SemanticToken]) -> Vec<u32> {
+        tokens
+            .iter()
+            .flat_map(|token| {
+                [
+                    token.delta_line,
+                    token.delta_start,
+                    token.length,
+                    token.token_type,
+                    token.token_modifiers_bitset,
+                ]
+            })
+            .collect()
+    }
+
+    /// What the client makes of `old` after `edits`.
+    fn apply(old: &[SemanticToken], edits: &[SemanticTokensEdit]) -> Vec<u32> {
+        let mut data = encode(old);
+        for edit in edits {
+            let start = edit.start as usize;
+            let end = start + edit.delete_count as usize;
+            data.splice(start..end, encode(edit.data.as_deref().unwrap_or_default()));
         }
+        data
+    }
+
+    #[test]
+    fn from_nothing() {
+        let new = [token(0, 0, 3), token(1, 4, 5)];
+        let edits = semantic_token_edits(&[], &new);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(apply(&[], &edits), encode(&new));
+    }
+
+    #[test]
+    fn nothing_changed() {
+        let tokens = [token(0, 0, 3), token(1, 4, 5)];
+        assert!(semantic_token_edits(&tokens, &tokens).is_empty());
+    }
+
+    #[test]
+    fn change_in_the_middle() {
+        let old = [
+            token(0, 0, 3),
+            token(1, 4, 5),
+            token(0, 6, 1),
+            token(2, 0, 3),
+        ];
+        let new = [token(0, 0, 3), token(1, 4, 7), token(2, 0, 3)];
+        let edits = semantic_token_edits(&old, &new);
+        assert_eq!(edits.len(), 1);
+        // Only the tokens in between are sent again.
+        assert_eq!(edits[0].start, 5);
+        assert_eq!(edits[0].delete_count, 10);
+        assert_eq!(edits[0].data.as_deref(), Some(&new[1..2]));
+        assert_eq!(apply(&old, &edits), encode(&new));
+    }
+
+    #[test]
+    fn tokens_removed() {
+        let old = [token(0, 0, 3), token(1, 4, 5), token(2, 0, 3)];
+        let new = [token(0, 0, 3), token(2, 0, 3)];
+        let edits = semantic_token_edits(&old, &new);
+        assert_eq!(edits[0].data, None);
+        assert_eq!(apply(&old, &edits), encode(&new));
     }
 }
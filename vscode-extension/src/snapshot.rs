@@ -0,0 +1,212 @@
+use pintc::predicate::Contract;
+
+/// A change to a document in byte offsets: `old_len` bytes at `start` were replaced by `new_len`
+/// bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edit {
+    pub start: usize,
+    pub old_len: usize,
+    pub new_len: usize,
+}
+
+impl Edit {
+    /// Where `offset` in the text before the edit ended up, unless it was replaced.
+    fn forward(&self, offset: usize) -> Option<usize> {
+        if offset <= self.start {
+            Some(offset)
+        } else if offset >= self.start + self.old_len {
+            Some(offset - self.old_len + self.new_len)
+        } else {
+            None
+        }
+    }
+
+    /// Where `offset` in the text after the edit was before it. Offsets in the inserted text map
+    /// to the start of the edit, where the half typed code is most likely picking up from.
+    fn backward(&self, offset: usize) -> usize {
+        if offset <= self.start {
+            offset
+        } else if offset >= self.start + self.new_len {
+            offset - self.new_len + self.old_len
+        } else {
+            self.start
+        }
+    }
+}
+
+/// The edits made to a document since its snapshot was taken, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct EditLog(Vec<Edit>);
+
+impl EditLog {
+    pub fn push(&mut self, edit: Edit) {
+        self.0.push(edit);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Map an offset in the snapshot's text to the current text. Offsets in text that has since
+    /// been replaced have no counterpart.
+    pub fn to_current(&self, offset: usize) -> Option<usize> {
+        self.0
+            .iter()
+            .try_fold(offset, |offset, edit| edit.forward(offset))
+    }
+
+    /// Map an offset in the current text to the snapshot's text.
+    pub fn to_snapshot(&self, offset: usize) -> usize {
+        self.0
+            .iter()
+            .rev()
+            .fold(offset, |offset, edit| edit.backward(offset))
+    }
+}
+
+/// Whether a change to a document that brings it to `version` can be applied to its `current`
+/// version. Changes are made against the version just before theirs, so one that is not newer
+/// was already applied or came out of order, and one further ahead follows changes that never
+/// arrived, unless it replaces the `full_text` anyway.
+pub fn check_version(current: i32, version: i32, full_text: bool) -> Result<(), String> {
+    if version <= current {
+        Err(format!("version {version} is not newer than {current}"))
+    } else if version > current + 1 && !full_text {
+        Err(format!("version {version} skips changes after {current}"))
+    } else {
+        Ok(())
+    }
+}
+
+/// The last analysis of a document that produced a contract. pintc rejects most half typed code,
+/// so requests are answered from this, with offsets mapped across the edits made since.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub contract: Contract,
+    /// The document version `contract` was built from.
+    pub version: i32,
+    pub edits: EditLog,
+}
+
+impl Snapshot {
+    pub fn new(contract: Contract, version: i32) -> Self {
+        Snapshot {
+            contract,
+            version,
+            edits: EditLog::default(),
+        }
+    }
+
+    /// Whether the document has moved on from the text `contract` was built from.
+    pub fn is_stale(&self, current_version: i32) -> bool {
+        self.version != current_version
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replace `range` of `text` with `with`, returning the edit that describes it.
+    fn replace(text: &mut String, range: std::ops::Range<usize>, with: &str) -> Edit {
+        let edit = Edit {
+            start: range.start,
+            old_len: range.len(),
+            new_len: with.len(),
+        };
+        text.replace_range(range, with);
+        edit
+    }
+
+    #[test]
+    fn offsets_before_an_edit_stay_put() {
+        let edit = Edit {
+            start: 10,
+            old_len: 4,
+            new_len: 6,
+        };
+        assert_eq!(edit.forward(3), Some(3));
+        assert_eq!(edit.forward(10), Some(10));
+        assert_eq!(edit.backward(3), 3);
+        assert_eq!(edit.backward(10), 10);
+    }
+
+    #[test]
+    fn offsets_after_an_edit_move_by_the_change_in_length() {
+        let edit = Edit {
+            start: 10,
+            old_len: 4,
+            new_len: 6,
+        };
+        assert_eq!(edit.forward(14), Some(16));
+        assert_eq!(edit.forward(20), Some(22));
+        assert_eq!(edit.backward(16), 14);
+        assert_eq!(edit.backward(22), 20);
+    }
+
+    #[test]
+    fn offsets_inside_an_edit() {
+        let edit = Edit {
+            start: 10,
+            old_len: 4,
+            new_len: 6,
+        };
+        // Replaced text is gone, and inserted text was never in the snapshot.
+        assert_eq!(edit.forward(12), None);
+        assert_eq!(edit.backward(13), 10);
+    }
+
+    #[test]
+    fn spans_across_edits_before_inside_and_after_them() {
+        let snapshot = "let a = b + c;";
+        let mut text = snapshot.to_string();
+        let mut edits = EditLog::default();
+        assert!(edits.is_empty());
+        edits.push(replace(&mut text, 0..0, "// x\n"));
+        let a = text.find(" a ").unwrap() + 1;
+        edits.push(replace(&mut text, a..a + 1, "total"));
+        let c = text.find('c').unwrap();
+        edits.push(replace(&mut text, c..c + 1, ""));
+        assert_eq!(text, "// x\nlet total = b + ;");
+
+        let b = snapshot.find('b').unwrap();
+        assert_eq!(edits.to_current(b), text.find('b'));
+        assert_eq!(edits.to_current(b + 1), Some(text.find('b').unwrap() + 1));
+        assert_eq!(edits.to_current(snapshot.len()), Some(text.len()));
+        assert_eq!(
+            edits.to_current(snapshot.find("a =").unwrap() + 1),
+            Some(a + 5)
+        );
+        assert_eq!(
+            edits.to_current(snapshot.find(';').unwrap()),
+            text.find(';')
+        );
+
+        assert_eq!(edits.to_snapshot(text.find('b').unwrap()), b);
+        assert_eq!(edits.to_snapshot(text.len()), snapshot.len());
+        // Where text was deleted maps to where it started.
+        assert_eq!(
+            edits.to_snapshot(text.find(';').unwrap()),
+            snapshot.find('c').unwrap()
+        );
+        assert_eq!(edits.to_snapshot(a + 2), snapshot.find('a').unwrap());
+    }
+
+    #[test]
+    fn accepts_the_next_version() {
+        assert_eq!(check_version(3, 4, false), Ok(()));
+    }
+
+    #[test]
+    fn rejects_versions_that_are_not_newer() {
+        assert!(check_version(3, 3, false).is_err());
+        assert!(check_version(3, 2, false).is_err());
+        assert!(check_version(3, 3, true).is_err());
+    }
+
+    #[test]
+    fn rejects_skipped_versions_unless_the_whole_text_is_replaced() {
+        assert!(check_version(3, 5, false).is_err());
+        assert_eq!(check_version(3, 5, true), Ok(()));
+    }
+}
@@ -0,0 +1,151 @@
+use crate::chumsky::{Ast, Decl};
+use crate::jump_definition::contains;
+
+/// A completion template in the LSP snippet syntax.
+pub struct Snippet {
+    pub label: &'static str,
+    pub detail: &'static str,
+    pub body: &'static str,
+}
+
+/// Templates for declarations at the top level of a module.
+const ITEM_SNIPPETS: &[Snippet] = &[
+    Snippet {
+        label: "predicate",
+        detail: "predicate declaration",
+        body: "predicate ${1:Name}($2) {\n\t$0\n}",
+    },
+    Snippet {
+        label: "storage",
+        detail: "storage block",
+        body: "storage {\n\t${1:name}: ${2:int},\n}",
+    },
+    Snippet {
+        label: "interface",
+        detail: "interface declaration",
+        body: "interface ${1:Name} {\n\tstorage {\n\t\t${2:name}: ${3:int},\n\t}\n\n\tpredicate ${4:Name}($5);\n}",
+    },
+    Snippet {
+        label: "const",
+        detail: "constant declaration",
+        body: "const ${1:NAME}: ${2:int} = $0;",
+    },
+    Snippet {
+        label: "type",
+        detail: "type alias",
+        body: "type ${1:Name} = ${0:int};",
+    },
+    Snippet {
+        label: "union",
+        detail: "union declaration",
+        body: "union ${1:Name} = ${2:A} | ${3:B};",
+    },
+    Snippet {
+        label: "macro",
+        detail: "macro declaration",
+        body: "macro @${1:name}(${2:\\$x}) {\n\t$0\n}",
+    },
+    Snippet {
+        label: "use",
+        detail: "use declaration",
+        body: "use ${1:module}::$0;",
+    },
+];
+
+/// Templates for the start of a statement in a predicate body.
+const STATEMENT_SNIPPETS: &[Snippet] = &[
+    Snippet {
+        label: "constraint",
+        detail: "constraint",
+        body: "constraint $0;",
+    },
+    Snippet {
+        label: "let",
+        detail: "variable declaration",
+        body: "let ${1:name}: ${2:int} = $0;",
+    },
+    Snippet {
+        label: "if",
+        detail: "if block",
+        body: "if ${1:condition} {\n\t$0\n}",
+    },
+    Snippet {
+        label: "match",
+        detail: "match block",
+        body: "match ${1:value} {\n\t${2:Union::Variant} => {\n\t\t$0\n\t}\n}",
+    },
+];
+
+/// Templates for expressions in a predicate body.
+const EXPRESSION_SNIPPETS: &[Snippet] = &[
+    Snippet {
+        label: "match",
+        detail: "match expression",
+        body: "match ${1:value} {\n\t${2:Union::Variant} => ${3:value},\n\telse => ${0:value},\n}",
+    },
+    Snippet {
+        label: "cond",
+        detail: "cond expression",
+        body: "cond {\n\t${1:condition} => ${2:value},\n\telse => ${0:value},\n}",
+    },
+    Snippet {
+        label: "forall",
+        detail: "forall generator",
+        body: "forall ${1:i} in ${2:0..N} {\n\t$0\n}",
+    },
+    Snippet {
+        label: "exists",
+        detail: "exists generator",
+        body: "exists ${1:i} in ${2:0..N} {\n\t$0\n}",
+    },
+];
+
+/// What can be written at a point of a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// A declaration, outside of any declaration.
+    Item,
+    /// A statement, at the start of one in a predicate body.
+    Statement,
+    /// An expression, elsewhere in a predicate body.
+    Expression,
+    /// Nothing that starts with a keyword, such as a name being declared.
+    Other,
+}
+
+/// What can be written at `offset` in `text`, of which `ast` is the syntax tree.
+pub fn placement_at(ast: &Ast, text: &str, offset: usize) -> Placement {
+    let Some(decl) = ast.iter().find(|decl| contains(&decl.span(), offset)) else {
+        return Placement::Item;
+    };
+    let Decl::Predicate { name, .. } = decl else {
+        // The half typed word itself parses as a bare expression, or as an error.
+        return match decl {
+            Decl::Expr(_) | Decl::Error(_) => Placement::Item,
+            _ => Placement::Other,
+        };
+    };
+    let Some(before) = text.get(..offset) else {
+        return Placement::Other;
+    };
+    let head = before
+        .trim_end_matches(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        .trim_end();
+    if offset <= name.span.end {
+        Placement::Other
+    } else if head.ends_with(['{', '}', ';']) {
+        Placement::Statement
+    } else {
+        Placement::Expression
+    }
+}
+
+/// The templates that fit at `offset` in `text`, of which `ast` is the syntax tree.
+pub fn snippets_at(ast: &Ast, text: &str, offset: usize) -> &'static [Snippet] {
+    match placement_at(ast, text, offset) {
+        Placement::Item => ITEM_SNIPPETS,
+        Placement::Statement => STATEMENT_SNIPPETS,
+        Placement::Expression => EXPRESSION_SNIPPETS,
+        Placement::Other => &[],
+    }
+}
@@ -0,0 +1,37 @@
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+
+use crate::chumsky::Ast;
+use crate::document_symbol::{document_symbols, ImCompleteDocumentSymbol};
+
+/// The symbols of `ast` worth finding from anywhere in the workspace: its top level declarations
+/// and the fields of its `storage` block, the latter with `storage` as their container.
+pub fn searchable_symbols(ast: &Ast) -> Vec<(ImCompleteDocumentSymbol, Option<String>)> {
+    let mut symbols = vec![];
+    for mut symbol in document_symbols(ast) {
+        let children = std::mem::take(&mut symbol.children);
+        // `storage` is a keyword, so nothing else can have its name.
+        if symbol.name == "storage" {
+            symbols.extend(
+                children
+                    .into_iter()
+                    .map(|field| (field, Some("storage".to_string()))),
+            );
+        } else {
+            symbols.push((symbol, None));
+        }
+    }
+    symbols
+}
+
+/// The items whose `name` fuzzily matches `query`, best matches first. An empty query matches
+/// everything.
+pub fn fuzzy_search<T>(query: &str, items: Vec<T>, name: impl Fn(&T) -> &str) -> Vec<T> {
+    let matcher = SkimMatcherV2::default();
+    let mut scored = items
+        .into_iter()
+        .filter_map(|item| Some((matcher.fuzzy_match(name(&item), query)?, item)))
+        .collect::<Vec<_>>();
+    scored.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+    scored.into_iter().map(|(_, item)| item).collect()
+}